@@ -1,162 +1,364 @@
-use axum::{extract::Path, response::IntoResponse, Json};
+use axum::{extract::{Path, Query}, response::IntoResponse, Json};
 use serde_json::{json, Value};
 use tokio::task;
-use std::{sync::{Arc, Mutex}, collections::LinkedList};
+use std::sync::{Arc, Mutex};
+use tracing::{info, info_span};
 use crate::HeaderMap;
 
-use crate::main_lib::manage_vm::{get_vm_config};
-use crate::main_lib::manage_pci::{get_pcis_info, add_pci_device, remove_pci_device};
-use crate::main_lib::structure::{HostPci, HostGpu, RequestPciData, RequestGpuData, Ticket,
-                                    generate_ticket, find_ticket, store_ticket, remove_ticket};
-
-fn extract_addresses(raw: &Value, target: &str) -> Vec<String> {
-    raw.as_array()
-        .unwrap()
-        .iter()
-        .filter(|device| {
-            let device_name = device["device_name"].as_str().unwrap();
-            device_name.contains(target)
-        })
-        .map(|device| device["address"].as_str().unwrap().to_string())
-        .collect()
-}
+use crate::main_lib::manage_vm::{get_vm_config, wait_device_removed, DEVICE_REMOVE_TIMEOUT};
+use crate::main_lib::manage_pci::{get_pcis_info, add_pci_device, remove_pci_device, 
+                                    release_pci_device, resolve_iommu_groups, resolve_pci_device_id,
+                                    attach_pci_devices, validate_pci_filter};
+use crate::main_lib::manage_gpu::{get_gpu_inventory, schedule_gpus, reserve_gpus};
+use crate::main_lib::mdev::{list_mdev_types, list_mdevs, create_mdev, remove_mdev, attach_mdev, 
+                            detach_mdev};
+use crate::main_lib::sriov::{list_sriov_devices, set_num_vfs, set_vf_config, reserve_vfs};
+use crate::main_lib::structure::{RequestPciData, RequestGpuData, RequestVfData, RequestSriovData, 
+                                RequestVfConfigData, RequestMdevData, PciFilter, HostPci, PciLedger, VmStatus, find_pci_by_device_id};
+use crate::main_lib::operations::{OperationList, create_operation, start_operation, find_operation,
+                                    push_operation_result, push_operation_error, finish_operation};
 
 pub async fn filter_get_vm_config(Path(vm_id): Path<String>) -> Json<Value> {
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
         Err(_) => return Json(json!({"Error": vm_id})),
     };
 
-    println!("\nGetting the vm config..");
+    info!("Getting the vm config");
     let configs = get_vm_config(vm_id);
     let configs_json: Value = serde_json::from_str(&configs).unwrap();
     Json(configs_json)
 }
 
-pub async fn filter_pcis_info(filter: &str, arg1: &str) -> Json<Value> {
-    println!("\nGetting the pcis info..");
-    let devices = get_pcis_info(filter, arg1).await;
+pub async fn filter_pcis_info(Query(filter): Query<PciFilter>, 
+                                pci_ledger: Arc<Mutex<PciLedger>>) -> Json<Value> {
+    info!("Validating the pci filter");
+    if let Err(e) = validate_pci_filter(&filter) {
+        return Json(json!({"Error": e}));
+    }
+
+    info!("Getting the pcis info");
+    let devices = get_pcis_info(&filter, &pci_ledger).await;
     Json(json!({ "devices": devices }))
 }
 
 pub async fn filter_add_pci(Path(vm_id): Path<String>, Json(payload): Json<RequestPciData>, 
-                            ticket_list: Arc<Mutex<LinkedList<Ticket>>>) 
+                            operations: OperationList, 
+                            pci_ledger: Arc<Mutex<PciLedger>>) 
                             -> impl IntoResponse {
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
         Err(_) => return Json(json!({"Error": vm_id})),
     };
 
-    println!("Generate the ticket id");
-    let payload_str = serde_json::to_string(&payload).unwrap();
-    let ticket_id = generate_ticket(vm_id, payload_str);
-    let ticket_id_cloned = ticket_id.clone();
-    let found_ticket = find_ticket(&ticket_id, &ticket_list);
-    
-    if found_ticket.is_none() {
-        task::spawn(async move {
-            let mut pcis_detail = Vec::new();
-            for pci in payload.hostpcis {
-                println!("\nTry passing through the device {}..", pci.address);
-                // Detail example is "{"id":"_vfio3","bdf":"0000:00:06.0"}"
-                let detail = add_pci_device(vm_id, &pci.address, 3);
-                let pci_json: Value = serde_json::from_str(&detail).unwrap();
-                pcis_detail.push(pci_json);
+    info!("Checking the IOMMU groups");
+    let addresses: Vec<String> = payload.hostpcis.iter().map(|pci| pci.address.clone()).collect();
+    let addresses = match resolve_iommu_groups(&addresses, payload.include_group) {
+        Ok(addresses) => addresses,
+        Err(e) => return Json(json!({"Error": e})),
+    };
+
+    info!("Creating the passthrough operation");
+    let operation_id = create_operation("passthrough", vm_id, &operations);
+    let operation_id_cloned = operation_id.clone();
+
+    let span = info_span!("operation", operation_id = %operation_id);
+    // ch-remote and sysfs calls block, they run on the blocking pool
+    task::spawn_blocking(move || {
+        let _entered = span.enter();
+        start_operation(&operation_id, &operations);
+        for address in addresses {
+            info!("Try passing through the device {}", address);
+            // Detail example is "{"id":"_vfio3","bdf":"0000:00:06.0"}"
+            match add_pci_device(vm_id, &address, 3, &pci_ledger) {
+                Ok(detail) => {
+                    let pci_json: Value = serde_json::from_str(&detail).unwrap_or_default();
+                    push_operation_result(&operation_id, pci_json, &operations);
+                }
+                Err(e) => push_operation_error(&operation_id, &e, &operations),
             }
-            store_ticket(vm_id, &ticket_id, pcis_detail, &ticket_list);
-        });
-    }
+        }
+        finish_operation(&operation_id, &operations);
+    });
 
+    // ticket_id is the same id, kept for the pt_status clients
     Json(json!({ 
-        "ticket_id": ticket_id_cloned
+        "operation_id": operation_id_cloned,
+        "ticket_id": operation_id_cloned
     }))
 }
 
-pub async fn filter_pt_status(headers: HeaderMap, ticket_list: Arc<Mutex<LinkedList<Ticket>>>) 
+// Kept for the clients polling the passthrough results with a ticket header
+pub async fn filter_pt_status(headers: HeaderMap, operations: OperationList) 
                                 -> impl IntoResponse {
-    println!("\nExtracting the ticket..");
-    let ticket_id = headers.get("ticket").unwrap().to_str().unwrap();
-    let found_ticket = find_ticket(&ticket_id, &ticket_list);
-
-    let mut pcis_detail = Vec::new();
-    if let Some(found_ticket) = found_ticket {
-        println!("\nFound the ticket returning..");
-        pcis_detail = found_ticket.pcis_detail;
-        remove_ticket(&ticket_id, &ticket_list);
-    }
+    info!("Extracting the ticket");
+    let ticket_id = headers.get("ticket").and_then(|value| value.to_str().ok()).unwrap_or("");
+    let pcis_detail = match find_operation(ticket_id, &operations) {
+        Some(operation) => operation.results,
+        None => Vec::new(),
+    };
 
     Json(json!({ 
         "hostpcis": pcis_detail
     }))
 }
 
+pub async fn filter_gpus_info(pci_ledger: Arc<Mutex<PciLedger>>) -> Json<Value> {
+    info!("Getting the gpus inventory");
+    let gpus = get_gpu_inventory(&pci_ledger);
+    Json(json!({ "gpus": gpus }))
+}
+
 pub async fn filter_add_gpu(Path(vm_id): Path<String>, Json(payload): Json<RequestGpuData>, 
-                            ticket_list: Arc<Mutex<LinkedList<Ticket>>>) 
+                            operations: OperationList, 
+                            pci_ledger: Arc<Mutex<PciLedger>>) 
                             -> impl IntoResponse {
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
         Err(_) => return Json(json!({"Error": vm_id})),
     };
 
-    println!("\nSearching for the gpu..");
-    let raw = json!(get_pcis_info(&"class_code", "3").await);
-    // let addresses = extract_addresses(&raw);
-
-    println!("Generate the ticket id");
-    let payload_str = serde_json::to_string(&payload).unwrap();
-    let ticket_id = generate_ticket(vm_id, payload_str);
-    let ticket_id_cloned = ticket_id.clone();
-    let found_ticket = find_ticket(&ticket_id, &ticket_list);
-
-    if found_ticket.is_none() {
-        task::spawn(async move {
-            let mut pcis_detail = Vec::new();
-            for gpu in payload.hostgpus {
-                let addresses = extract_addresses(&raw, &gpu.device_name);
-                for i in 0..gpu.amount {
-                    // Skip if the resource is duplicated or busy
-                    let mut j = i;
-                    while j < addresses.len() as i32 {
-                        let detail = add_pci_device(vm_id, &addresses[j as usize], 3);
-                        if detail != "None" {
-                            let pci_json: Value = serde_json::from_str(&detail).unwrap();
-                            pcis_detail.push(pci_json);
-                            break;
-                        }
-                        j += 1;
-                    }
+    info!("Scheduling the gpus");
+    let inventory = get_gpu_inventory(&pci_ledger);
+    let selected = match schedule_gpus(&payload.hostgpus, &inventory) {
+        Ok(selected) => selected,
+        Err(e) => return Json(json!({"Error": e})),
+    };
+
+    // Companion functions such as the HDMI audio share the GPU group
+    let devices = match reserve_gpus(vm_id, &selected, &pci_ledger) {
+        Ok(devices) => devices,
+        Err(e) => return Json(json!({"Error": e})),
+    };
+
+    info!("Creating the gpu passthrough operation");
+    let operation_id = create_operation("gpu_passthrough", vm_id, &operations);
+    let operation_id_cloned = operation_id.clone();
+
+    let span = info_span!("operation", operation_id = %operation_id);
+    // ch-remote and sysfs calls block, they run on the blocking pool
+    task::spawn_blocking(move || {
+        let _entered = span.enter();
+        start_operation(&operation_id, &operations);
+        match attach_pci_devices(vm_id, &devices, &pci_ledger) {
+            Ok(details) => {
+                for detail in details {
+                    let pci_json: Value = serde_json::from_str(&detail).unwrap_or_default();
+                    push_operation_result(&operation_id, pci_json, &operations);
                 }
             }
-        });
-    }
+            Err(e) => push_operation_error(&operation_id, &e, &operations),
+        }
+        finish_operation(&operation_id, &operations);
+    });
    
+    // ticket_id is the same id, kept for the pt_status clients
     Json(json!({ 
-        "ticket_id": ticket_id_cloned
+        "operation_id": operation_id_cloned,
+        "gpus": selected,
+        "ticket_id": operation_id_cloned
     }))
 }
 
-pub async fn filter_remove_pci(Path(vm_id): Path<String>, Json(payload): Json<RequestPciData>) 
-                                -> impl IntoResponse {
-    println!("\nValidating the vm id..");
+pub async fn filter_sriov_info(pci_ledger: Arc<Mutex<PciLedger>>) -> Json<Value> {
+    info!("Getting the SR-IOV devices");
+    let devices = list_sriov_devices(&pci_ledger);
+    Json(json!({ "devices": devices }))
+}
+
+pub async fn filter_set_num_vfs(Path(address): Path<String>, Json(payload): Json<RequestSriovData>, 
+                                pci_ledger: Arc<Mutex<PciLedger>>) -> Json<Value> {
+    info!("Setting the VFs of {}", address);
+    match set_num_vfs(&address, payload.num_vfs, &pci_ledger) {
+        Ok(info) => Json(json!(info)),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_set_vf_config(Path((address, index)): Path<(String, String)>, 
+                                    Json(payload): Json<RequestVfConfigData>) -> Json<Value> {
+    info!("Validating the vf index");
+    let index: u32 = match index.parse() {
+        Ok(index) => index,
+        Err(_) => return Json(json!({"Error": index})),
+    };
+
+    info!("Setting the VF {} of {}", index, address);
+    match set_vf_config(&address, index, payload.mac.as_deref(), payload.vlan) {
+        Ok(_) => Json(json!({"address": address, "index": index, "mac": payload.mac, 
+                            "vlan": payload.vlan})),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_add_vf(Path(vm_id): Path<String>, Json(payload): Json<RequestVfData>, 
+                            operations: OperationList, 
+                            pci_ledger: Arc<Mutex<PciLedger>>) 
+                            -> impl IntoResponse {
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
         Err(_) => return Json(json!({"Error": vm_id})),
     };
 
-    for pci in payload.hostpcis {
-        println!("\nTry removing the passing through device {}..", pci.address);
-        // Detail example is "{"id":"_vfio3","bdf":"0000:00:06.0"}"
-        let _ = remove_pci_device(vm_id, &pci.address);
+    info!("Reserving the VFs");
+    let vfs = match reserve_vfs(vm_id, &payload.hostvfs, &pci_ledger) {
+        Ok(vfs) => vfs,
+        Err(e) => return Json(json!({"Error": e})),
+    };
+
+    info!("Creating the VF passthrough operation");
+    let operation_id = create_operation("vf_passthrough", vm_id, &operations);
+    let operation_id_cloned = operation_id.clone();
+    let vfs_cloned = vfs.clone();
+
+    let span = info_span!("operation", operation_id = %operation_id);
+    // ch-remote and sysfs calls block, they run on the blocking pool
+    task::spawn_blocking(move || {
+        let _entered = span.enter();
+        start_operation(&operation_id, &operations);
+        match attach_pci_devices(vm_id, &vfs, &pci_ledger) {
+            Ok(details) => {
+                for detail in details {
+                    let pci_json: Value = serde_json::from_str(&detail).unwrap_or_default();
+                    push_operation_result(&operation_id, pci_json, &operations);
+                }
+            }
+            Err(e) => push_operation_error(&operation_id, &e, &operations),
+        }
+        finish_operation(&operation_id, &operations);
+    });
+
+    Json(json!({ 
+        "operation_id": operation_id_cloned,
+        "vfs": vfs_cloned
+    }))
+}
+
+pub async fn filter_mdev_types() -> Json<Value> {
+    info!("Getting the mdev types");
+    Json(json!({ "devices": list_mdev_types() }))
+}
+
+pub async fn filter_mdevs_info() -> Json<Value> {
+    info!("Getting the mdevs");
+    Json(json!({ "mdevs": list_mdevs() }))
+}
+
+pub async fn filter_create_mdev(Json(payload): Json<RequestMdevData>) -> Json<Value> {
+    let (parent, mdev_type) = match (payload.parent.as_deref(), payload.mdev_type.as_deref()) {
+        (Some(parent), Some(mdev_type)) => (parent, mdev_type),
+        _ => return Json(json!({"Error": "parent and mdev_type are required"})),
+    };
+
+    info!("Creating the mdev");
+    match create_mdev(parent, mdev_type, payload.uuid.as_deref()) {
+        Ok(info) => Json(json!(info)),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_remove_mdev(Path(uuid): Path<String>) -> Json<Value> {
+    info!("Removing the mdev {}", uuid);
+    match remove_mdev(&uuid) {
+        Ok(_) => Json(json!({"uuid": uuid, "removed": true})),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_attach_mdev(Path(vm_id): Path<String>, Json(payload): Json<RequestMdevData>, 
+                                vm_vec: Arc<Mutex<Vec<VmStatus>>>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    info!("Attaching the mdev");
+    match task::spawn_blocking(move || attach_mdev(&vm_vec, vm_id, &payload)).await {
+        Ok(Ok(mdev)) => Json(json!(mdev)),
+        Ok(Err(e)) => Json(json!({"Error": e})),
+        Err(e) => Json(json!({"Error": e.to_string()})),
     }
+}
+
+pub async fn filter_detach_mdev(Path((vm_id, uuid)): Path<(String, String)>, 
+                                vm_vec: Arc<Mutex<Vec<VmStatus>>>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    info!("Detaching the mdev {}", uuid);
+    let uuid_cloned = uuid.clone();
+    match task::spawn_blocking(move || detach_mdev(&vm_vec, vm_id, &uuid_cloned)).await {
+        Ok(Ok(_)) => Json(json!({"uuid": uuid, "removed": true})),
+        Ok(Err(e)) => Json(json!({"Error": e})),
+        Err(e) => Json(json!({"Error": e.to_string()})),
+    }
+}
+
+fn remove_pci_devices(vm_id: i16, hostpcis: Vec<HostPci>, pci_ledger: &Arc<Mutex<PciLedger>>) -> Vec<Value> {
+    let mut pcis_detail = Vec::new();
+    for pci in hostpcis {
+        info!("Try removing the passing through device {}", pci.address);
+        let device_id = match resolve_pci_device_id(vm_id, &pci.address, pci_ledger) {
+            Ok(device_id) => device_id,
+            Err(e) => {
+                pcis_detail.push(json!({"address": pci.address, "removed": false, "error": e}));
+                continue;
+            }
+        };
+
+        match remove_pci_device(vm_id, &device_id) {
+            Ok(_) if !wait_device_removed(vm_id, &device_id, DEVICE_REMOVE_TIMEOUT) => {
+                // The host driver cannot take the device back while the guest still holds it
+                pcis_detail.push(json!({"address": pci.address, "id": device_id, "removed": false, 
+                                        "error": "The guest did not release the device, it stays reserved"}));
+            }
+            Ok(_) => {
+                let bdf = if pci.address.contains(':') {
+                    Some(pci.address.clone())
+                } else {
+                    find_pci_by_device_id(vm_id, &device_id, pci_ledger)
+                };
+                if let Some(bdf) = bdf {
+                    release_pci_device(vm_id, &bdf, pci_ledger);
+                }
+                pcis_detail.push(json!({"address": pci.address, "id": device_id, "removed": true}));
+            }
+            Err(e) => {
+                pcis_detail.push(json!({"address": pci.address, "id": device_id, "removed": false, 
+                                        "error": e}));
+            }
+        }
+    }
+    pcis_detail
+}
+
+pub async fn filter_remove_pci(Path(vm_id): Path<String>, Json(payload): Json<RequestPciData>, 
+                                pci_ledger: Arc<Mutex<PciLedger>>) -> impl IntoResponse {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    // Waiting for the guest to release the devices blocks, keep it off the async workers
+    let pcis_detail = task::spawn_blocking(move || remove_pci_devices(vm_id, payload.hostpcis, &pci_ledger))
+        .await
+        .unwrap_or_default();
    
-    println!("\nGetting the vm config..");
+    info!("Getting the vm config");
     let configs = get_vm_config(vm_id);
-    let configs_json: Value = serde_json::from_str(&configs).unwrap();
+    let configs_json: Value = serde_json::from_str(&configs).unwrap_or_default();
 
     Json(json!({ 
+        "hostpcis": pcis_detail,
         "config": configs_json
     }))
 }
\ No newline at end of file
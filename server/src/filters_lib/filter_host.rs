@@ -0,0 +1,16 @@
+use std::sync::{Arc, Mutex};
+use axum::{extract::Path, Json};
+use serde_json::{json, Value};
+use tokio::task;
+use tracing::{info};
+
+use crate::main_lib::structure::PciLedger;
+use crate::main_lib::host::get_host_resource;
+
+pub async fn filter_host_info(Path(node): Path<String>, pci_ledger: Arc<Mutex<PciLedger>>) -> Json<Value> {
+    info!("Getting the host resources");
+    match task::spawn_blocking(move || get_host_resource(&node, &pci_ledger)).await {
+        Ok(host) => Json(json!(host)),
+        Err(e) => Json(json!({"Error": e.to_string()})),
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::Instant;
+use axum::{extract::{MatchedPath, RawPathParams, Request}, http::HeaderValue, middleware::Next, 
+           response::IntoResponse};
+use tracing::{field, info, info_span, Instrument};
+use uuid::Uuid;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Open a span per request carrying the request id, the route and the vm id when the route has one,
+// the request id is taken from the client when given and echoed back in the response
+pub async fn trace_requests(params: Result<RawPathParams, axum::extract::rejection::RawPathParamsRejection>,
+                            request: Request, next: Next) -> impl IntoResponse {
+    let request_id = request.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let route = request.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let method = request.method().to_string();
+
+    let span = info_span!("request", request_id = %request_id, method = %method, route = %route, 
+                          vm_id = field::Empty);
+    if let Ok(params) = params {
+        if let Some((_, vm_id)) = params.iter().find(|(key, _)| *key == "vm_id") {
+            match vm_id.parse::<i64>() {
+                Ok(vm_id) => span.record("vm_id", vm_id),
+                Err(_) => span.record("vm_id", vm_id),
+            };
+        }
+    }
+
+    let start = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| info!(status = response.status().as_u16(), 
+                           elapsed_ms = start.elapsed().as_millis() as u64, "Request finished"));
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,29 @@
+use std::{sync::{Arc, Mutex}, time::Instant};
+use axum::{extract::{MatchedPath, Request}, http::{header, StatusCode}, middleware::Next, 
+            response::{IntoResponse, Response}};
+use tokio::task;
+
+use crate::main_lib::structure::{VmStatus, MonitorStats};
+use crate::main_lib::metrics::{MetricsState, record_request, render_metrics};
+
+pub async fn filter_metrics(metrics: MetricsState, vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
+                            monitor_stats: Arc<Mutex<MonitorStats>>) -> impl IntoResponse {
+    let rendered = task::spawn_blocking(move || render_metrics(&metrics, &vm_vec, &monitor_stats)).await;
+    match rendered {
+        Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, "text/plain")], e.to_string()),
+    }
+}
+
+// Record the latency of every request by its route template
+pub async fn track_requests(metrics: MetricsState, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or("unmatched".to_string());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+    record_request(&metrics, &method, &route, response.status().as_u16(), started.elapsed().as_secs_f64());
+    response
+}
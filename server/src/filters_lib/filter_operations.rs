@@ -0,0 +1,14 @@
+use axum::{extract::Path, response::IntoResponse, http::StatusCode, Json};
+use serde_json::json;
+use tracing::{info};
+
+use crate::main_lib::operations::{OperationList, find_operation};
+
+pub async fn filter_get_operation(Path(operation_id): Path<String>, operations: OperationList) 
+                                    -> impl IntoResponse {
+    info!("Getting the operation {}", operation_id);
+    match find_operation(&operation_id, &operations) {
+        Some(operation) => (StatusCode::OK, Json(json!(operation))),
+        None => (StatusCode::NOT_FOUND, Json(json!({"Error": "Operation not found"}))),
+    }
+}
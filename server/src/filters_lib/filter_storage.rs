@@ -0,0 +1,113 @@
+use axum::{extract::Path, Json};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::task;
+use tracing::{info};
+
+use crate::main_lib::structure::{VmStatus, RequestDiskSizeData, RequestPoolData, RequestQuotaData, 
+                                    RequestDiskData, RequestRateLimitData};
+use crate::main_lib::manage_storage::{resize_disk, attach_disk, set_disk_rate_limit, list_pools, get_pool, add_pool, remove_pool, 
+                                        get_quotas, set_quotas};
+
+pub async fn filter_resize_disk(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
+                                Path((vm_id, disk)): Path<(String, String)>,
+                                Json(payload): Json<RequestDiskSizeData>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    info!("Resizing the disk {}", disk);
+    // qemu-img and the VMM API block, keep them off the async workers
+    match task::spawn_blocking(move || resize_disk(&vm_vec, vm_id, &disk, &payload.size)).await {
+        Ok(Ok(disk)) => Json(json!({
+            "vm_id": vm_id,
+            "disk": disk,
+        })),
+        Ok(Err(e)) => Json(json!({"Error": e})),
+        Err(e) => Json(json!({"Error": e.to_string()})),
+    }
+}
+
+pub async fn filter_attach_disk(vm_vec: Arc<Mutex<Vec<VmStatus>>>, Path(vm_id): Path<String>,
+                                Json(payload): Json<RequestDiskData>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    info!("Attaching the disk {}", payload.id);
+    match task::spawn_blocking(move || attach_disk(&vm_vec, vm_id, &payload)).await {
+        Ok(Ok(disk)) => Json(json!({
+            "vm_id": vm_id,
+            "disk": disk,
+        })),
+        Ok(Err(e)) => Json(json!({"Error": e})),
+        Err(e) => Json(json!({"Error": e.to_string()})),
+    }
+}
+
+pub async fn filter_set_disk_rate_limit(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
+                                        Path((vm_id, disk)): Path<(String, String)>,
+                                        Json(payload): Json<RequestRateLimitData>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    info!("Setting the rate limit of the disk {}", disk);
+    match task::spawn_blocking(move || set_disk_rate_limit(&vm_vec, vm_id, &disk, &payload)).await {
+        Ok(Ok((disk, pending))) => Json(json!({
+            "vm_id": vm_id,
+            "disk": disk,
+            "pending_restart": pending,
+        })),
+        Ok(Err(e)) => Json(json!({"Error": e})),
+        Err(e) => Json(json!({"Error": e.to_string()})),
+    }
+}
+
+pub async fn filter_list_pools() -> Json<Value> {
+    info!("Getting the storage pools");
+    Json(json!({ "pools": list_pools() }))
+}
+
+pub async fn filter_get_pool(Path(pool): Path<String>) -> Json<Value> {
+    info!("Getting the storage pool {}", pool);
+    match get_pool(&pool) {
+        Ok(pool) => Json(pool),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_add_pool(Json(payload): Json<RequestPoolData>) -> Json<Value> {
+    info!("Adding the storage pool {}", payload.name);
+    match add_pool(&payload.name, &payload.path) {
+        Ok(pool) => Json(pool),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_remove_pool(Path(pool): Path<String>) -> Json<Value> {
+    info!("Removing the storage pool {}", pool);
+    match remove_pool(&pool) {
+        Ok(_) => Json(json!({"name": pool})),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_get_quotas() -> Json<Value> {
+    info!("Getting the storage quotas");
+    Json(get_quotas())
+}
+
+pub async fn filter_set_quotas(Json(payload): Json<RequestQuotaData>) -> Json<Value> {
+    info!("Setting the storage quotas");
+    match set_quotas(&payload) {
+        Ok(quotas) => Json(quotas),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
@@ -1,91 +1,272 @@
-use std::{sync::{Arc, Mutex}, thread};
-use axum::{extract::Path, http::{StatusCode}};
+use std::{sync::{Arc, Mutex}, io::Write, thread};
+use tokio::task;
+use axum::{extract::{Path, Query, ws::{Message, WebSocket, WebSocketUpgrade}}, http::{header, StatusCode}, 
+           response::{IntoResponse, Response}, Json};
+use tokio::sync::broadcast::error::RecvError;
+use serde_json::{json, Value};
+use tracing::{error, info, info_span, warn};
 
-use crate::main_lib::structure::{VmStatus};
-use crate::main_lib::manage_vm::{start_vm, force_terminate, delete_vm, shutdown_vm};
+use crate::main_lib::structure::{MAXVM, VmStatus, MonitorStats, PciLedger, RequestProbeData, 
+                                StatsQuery, StatsSample, ConsoleLogQuery, ConsoleQuery};
+use crate::main_lib::vm_stats::{collect_vm_stats};
+use crate::main_lib::manage_vm::{run_vm_operation, force_terminate, delete_vm, shutdown_vm};
+use crate::main_lib::init_vm::{rebuild_seed};
+use crate::main_lib::health::{set_vm_probes};
+use crate::main_lib::console::{read_console_log, open_console_session, ConsoleSession};
+use crate::main_lib::operations::{OperationList, create_operation, start_operation};
 
-pub async fn filter_start_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
-                            Path(vm_id): Path<String>) -> StatusCode {
+pub async fn filter_start_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, pci_ledger: Arc<Mutex<PciLedger>>,
+                            operations: OperationList, Path(vm_id): Path<String>) -> impl IntoResponse {
     
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
-        Err(_) => return StatusCode::METHOD_NOT_ALLOWED,
+        Err(_) => return (StatusCode::METHOD_NOT_ALLOWED, Json(json!({"Error": vm_id}))),
     };
 
-    let config_path = format!("../vms-config/{}", vm_id);
+    let operation_id = create_operation("start", vm_id, &operations);
+    let operation_id_cloned = operation_id.clone();
+    let span = info_span!("operation", operation_id = %operation_id);
     thread::spawn(move || {
-        println!("\nRunning the VM..");
-        let vm_status = start_vm(&vm_vec, vm_id, &config_path);
-        if vm_status != 1 {
-            println!("\nError: Cannot boot the VM.");
-        }
+        let _entered = span.enter();
+        info!("Running the VM");
+        start_operation(&operation_id, &operations);
+        let _ = run_vm_operation(&vm_vec, &pci_ledger, &operations, vm_id, &operation_id);
     });
 
-    StatusCode::ACCEPTED
+    (StatusCode::ACCEPTED, Json(json!({"operation_id": operation_id_cloned})))
 }
 
-pub async fn filter_stop_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
+pub async fn filter_stop_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, pci_ledger: Arc<Mutex<PciLedger>>,
                             Path(vm_id): Path<String>) -> StatusCode {
     
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
         Err(_) => return StatusCode::METHOD_NOT_ALLOWED,
     };
 
-    println!("\nForce terminating the vm..");
-    force_terminate(&vm_vec, vm_id);
+    info!("Force terminating the vm");
+    let _ = task::spawn_blocking(move || force_terminate(&vm_vec, &pci_ledger, vm_id)).await;
     StatusCode::ACCEPTED
 }
 
 pub async fn filter_shutdown_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
     Path(vm_id): Path<String>) -> StatusCode {
 
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
     Ok(id) => id,
     Err(_) => return StatusCode::METHOD_NOT_ALLOWED,
     };
 
-    println!("\nShutting down the vm..");
+    info!("Shutting down the vm");
     shutdown_vm(&vm_vec, vm_id);
     StatusCode::ACCEPTED
 }
 
-pub async fn filter_reboot_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
-                            Path(vm_id): Path<String>) -> StatusCode {
-    println!("\nValidating the vm id..");
+pub async fn filter_reboot_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, pci_ledger: Arc<Mutex<PciLedger>>,
+                            operations: OperationList, Path(vm_id): Path<String>) -> impl IntoResponse {
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
-        Err(_) => return StatusCode::METHOD_NOT_ALLOWED,
+        Err(_) => return (StatusCode::METHOD_NOT_ALLOWED, Json(json!({"Error": vm_id}))),
     };
 
-    println!("\nForce terminating the vm..");
-    force_terminate(&vm_vec, vm_id);
+    info!("Force terminating the vm");
+    let vm_vec_cloned = Arc::clone(&vm_vec);
+    let pci_ledger_cloned = Arc::clone(&pci_ledger);
+    let _ = task::spawn_blocking(move || force_terminate(&vm_vec_cloned, &pci_ledger_cloned, vm_id)).await;
 
-    let config_path = format!("../vms-config/{}", vm_id);
+    let operation_id = create_operation("reboot", vm_id, &operations);
+    let operation_id_cloned = operation_id.clone();
+    let span = info_span!("operation", operation_id = %operation_id);
     thread::spawn(move || {
-        println!("\nRunning the VM..");
-        let vm_status = start_vm(&vm_vec, vm_id, &config_path);
-        if vm_status != 1 {
-            println!("\nError: Cannot boot the VM.");
-        }
+        let _entered = span.enter();
+        info!("Running the VM");
+        start_operation(&operation_id, &operations);
+        let _ = run_vm_operation(&vm_vec, &pci_ledger, &operations, vm_id, &operation_id);
     });
 
-    StatusCode::ACCEPTED
+    (StatusCode::ACCEPTED, Json(json!({"operation_id": operation_id_cloned})))
 }
 
-pub async fn filter_delete_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
+pub async fn filter_delete_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, pci_ledger: Arc<Mutex<PciLedger>>,
                         Path(vm_id): Path<String>) -> StatusCode {
 
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: i16 = match vm_id.parse() {
         Ok(id) => id,
         Err(_) => return StatusCode::METHOD_NOT_ALLOWED,
     };
 
-    println!("\nDeleting the vm..");
-    delete_vm(&vm_vec, vm_id);
+    info!("Deleting the vm");
+    let _ = task::spawn_blocking(move || delete_vm(&vm_vec, &pci_ledger, vm_id)).await;
     StatusCode::ACCEPTED
-}
\ No newline at end of file
+}
+
+pub async fn filter_rebuild_seed(Path(vm_id): Path<String>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    info!("Rebuilding the cloud-init seed");
+    match rebuild_seed(vm_id) {
+        Ok(instance_id) => Json(json!({
+            "vm_id": vm_id,
+            "instance_id": instance_id,
+        })),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_set_probes(Path(vm_id): Path<String>, 
+                                Json(payload): Json<RequestProbeData>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) => id,
+        Err(_) => return Json(json!({"Error": vm_id})),
+    };
+
+    info!("Setting the guest probes");
+    let probes = payload.probes.clone();
+    match set_vm_probes(vm_id, payload.probes) {
+        Ok(_) => Json(json!({
+            "vm_id": vm_id,
+            "probes": probes,
+        })),
+        Err(e) => Json(json!({"Error": e})),
+    }
+}
+
+pub async fn filter_monitor_status(vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
+                                    monitor_stats: Arc<Mutex<MonitorStats>>) -> Json<Value> {
+    info!("Getting the monitor status");
+    let monitor_stats = monitor_stats.lock().unwrap().clone();
+    let vms: Vec<Value> = {
+        let vm_vec = vm_vec.lock().unwrap();
+        (0..MAXVM)
+            .filter(|vm_id| vm_vec[*vm_id].status > 0)
+            .map(|vm_id| json!({
+                "vm_id": vm_id,
+                "vmm_state": vm_vec[vm_id].health.vmm_state,
+                "checked_at": vm_vec[vm_id].health.checked_at,
+                "probe_latency_ms": vm_vec[vm_id].health.probe_latency_ms,
+            }))
+            .collect()
+    };
+
+    Json(json!({
+        "last_run_at": monitor_stats.last_run_at,
+        "last_run_duration_ms": monitor_stats.last_run_duration_ms,
+        "vms_checked": monitor_stats.vms_checked,
+        "probes_timed_out": monitor_stats.probes_timed_out,
+        "vms": vms,
+    }))
+}
+
+pub async fn filter_vm_stats(vm_vec: Arc<Mutex<Vec<VmStatus>>>, Path(vm_id): Path<String>, 
+                                Query(query): Query<StatsQuery>) -> Json<Value> {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) if (0..MAXVM as i16).contains(&id) => id,
+        _ => return Json(json!({"Error": vm_id})),
+    };
+
+    let pid = vm_vec.lock().unwrap()[vm_id as usize].process_id.to_string();
+    info!("Collecting the vm stats");
+    let stats = match task::spawn_blocking(move || collect_vm_stats(vm_id, &pid)).await {
+        Ok(Ok(stats)) => stats,
+        Ok(Err(e)) => return Json(json!({"Error": e})),
+        Err(e) => return Json(json!({"Error": e.to_string()})),
+    };
+
+    let mut stats_json = json!(stats);
+    if query.history {
+        let history: Vec<StatsSample> = vm_vec.lock().unwrap()[vm_id as usize].history
+            .iter().cloned().collect();
+        stats_json["history"] = json!(history);
+    }
+    Json(stats_json)
+}
+
+const CONSOLE_LOG_TAIL: usize = 100;
+
+pub async fn filter_console_log(Path(vm_id): Path<String>, Query(query): Query<ConsoleLogQuery>) -> Response {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) if (0..MAXVM as i16).contains(&id) => id,
+        _ => return Json(json!({"Error": vm_id})).into_response(),
+    };
+
+    info!("Reading the console log");
+    let tail = query.tail.unwrap_or(CONSOLE_LOG_TAIL);
+    match task::spawn_blocking(move || read_console_log(vm_id, tail)).await {
+        Ok(Ok(log)) => ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], log).into_response(),
+        Ok(Err(e)) => (StatusCode::NOT_FOUND, Json(json!({"Error": e}))).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"Error": e.to_string()}))).into_response(),
+    }
+}
+
+pub async fn filter_console(ws: WebSocketUpgrade, Path(vm_id): Path<String>, 
+                            Query(query): Query<ConsoleQuery>) -> Response {
+    info!("Validating the vm id");
+    let vm_id: i16 = match vm_id.parse() {
+        Ok(id) if (0..MAXVM as i16).contains(&id) => id,
+        _ => return Json(json!({"Error": vm_id})).into_response(),
+    };
+    let read_write = match query.mode.as_deref() {
+        None | Some("rw") => true,
+        Some("ro") => false,
+        Some(mode) => return Json(json!({"Error": format!("Invalid console mode: '{}'", mode)})).into_response(),
+    };
+
+    info!("Opening the console session");
+    let session = match open_console_session(vm_id, read_write) {
+        Ok(session) => session,
+        Err((code, e)) => {
+            let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            return (status, Json(json!({"Error": e}))).into_response();
+        }
+    };
+    ws.on_upgrade(move |socket| bridge_console(socket, session))
+}
+
+// Forward the serial output to the client and, for the read-write session, the client input to the pty
+async fn bridge_console(mut socket: WebSocket, session: ConsoleSession) {
+    let ConsoleSession { mut output, input } = session;
+    info!(read_write = input.is_some(), "Console session started");
+    loop {
+        tokio::select! {
+            data = output.recv() => match data {
+                Ok(data) => {
+                    if socket.send(Message::Binary(data.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => warn!("The console session skipped {} chunks", skipped),
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => {
+                let data = match message {
+                    Some(Ok(Message::Binary(data))) => data.to_vec(),
+                    Some(Ok(Message::Text(text))) => text.as_bytes().to_vec(),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                // Observers cannot type into the console
+                let Some((pty, _)) = input.as_ref() else { continue };
+                let pty = Arc::clone(pty);
+                let written = task::spawn_blocking(move || pty.lock().unwrap().write_all(&data)).await;
+                if !matches!(written, Ok(Ok(_))) {
+                    error!("Cannot write to the console");
+                    break;
+                }
+            }
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+    info!("Console session closed");
+}
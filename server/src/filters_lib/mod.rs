@@ -1,2 +1,7 @@
 pub mod filter_hardware;
-pub mod filter_vm_manage;
\ No newline at end of file
+pub mod filter_vm_manage;
+pub mod filter_storage;
+pub mod filter_operations;
+pub mod filter_metrics;
+pub mod filter_host;
+pub mod filter_logging;
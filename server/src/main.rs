@@ -1,77 +1,320 @@
-use std::{sync::{Arc, Mutex}, fs, thread, collections::LinkedList};
-use axum::{Router, extract::Path, routing::{post, get, put, delete}, http::{HeaderMap}, 
-            response::IntoResponse, Json};
+use std::{sync::{Arc, Mutex}, fs, thread, time::Duration, collections::{HashMap, BTreeMap}};
+use axum::{Router, extract::Path, routing::{post, get, put, delete}, http::{HeaderMap, StatusCode}, 
+            response::{IntoResponse, Response}, middleware, Json};
 use serde::{Serialize};
 use serde_json::{json};
+use uuid::Uuid;
+use tokio::task;
+use tracing::{info, info_span, warn, Span};
 
 // Main libraries
 mod main_lib;
-use main_lib::structure::{STATUS, MAXVM, VmStatus, Ticket, init_vm_vec, find_free_slot};
-use main_lib::init_vm::{get_cloud_image, write_cloud_config, create_cloud_init_files, 
-                        write_vm_config, run_cloud_init};
-use main_lib::manage_vm::{start_vm, resize_storage, monitor_vms};
+use main_lib::structure::{STATUS, MAXVM, VmStatus, MonitorStats, VmSpec, RequestVmData, PciLedger,
+                        init_vm_vec, find_free_slot, load_pci_ledger};
+use main_lib::init_vm::{get_cloud_image, fetch_cloud_image, get_image_size, write_cloud_config, create_cloud_init_files, 
+                        write_vm_config, run_cloud_init, save_vm_spec, load_vm_spec, lock_vm_spec};
+use main_lib::operations::{OperationList, create_operation, start_operation, push_operation_error};
+use main_lib::cloud_init::{validate_cloud_data, validate_hostname, validate_fqdn, validate_metadata};
+use main_lib::manage_vm::{run_vm_operation, delete_vm, resize_storage, monitor_vms, wait_vm_ready, MAX_WAIT_READY};
+use main_lib::health::{validate_probes};
+use main_lib::metrics::{MetricsState, new_metrics};
+use main_lib::host::{ADMISSION_LOCK, check_admission};
+use main_lib::logging::{init_logging};
+use main_lib::manage_pci::{build_boot_devices, reserve_boot_devices, release_vm_pci_devices};
+use main_lib::manage_storage::{parse_size, default_disks, get_disk_size, 
+                                find_pool, vm_disk_dir, check_storage, parse_rate_limit, DEFAULT_POOL};
 
 // Preprocessing libraries
 mod filters_lib;
 use filters_lib::filter_vm_manage::{filter_start_vm, filter_stop_vm, filter_reboot_vm, 
-                                    filter_delete_vm, filter_shutdown_vm};
+                                    filter_delete_vm, filter_shutdown_vm, filter_rebuild_seed, 
+                                    filter_set_probes, filter_monitor_status, filter_vm_stats, 
+                                    filter_console_log, filter_console};
+use filters_lib::filter_storage::{filter_resize_disk, filter_attach_disk, filter_set_disk_rate_limit, 
+                                    filter_list_pools, filter_get_pool, 
+                                    filter_add_pool, filter_remove_pool, filter_get_quotas, 
+                                    filter_set_quotas};
 use filters_lib::filter_hardware::{filter_get_vm_config, filter_pcis_info, filter_add_pci, 
-                                    filter_add_gpu, filter_remove_pci, filter_pt_status};
+                                    filter_add_gpu, filter_remove_pci, filter_pt_status,
+                                    filter_gpus_info, filter_sriov_info, filter_set_num_vfs, 
+                                    filter_set_vf_config, filter_add_vf, filter_mdev_types, 
+                                    filter_mdevs_info, filter_create_mdev, filter_remove_mdev, 
+                                    filter_attach_mdev, filter_detach_mdev};
+use filters_lib::filter_operations::{filter_get_operation};
+use filters_lib::filter_metrics::{filter_metrics, track_requests};
+use filters_lib::filter_host::{filter_host_info};
+use filters_lib::filter_logging::{trace_requests};
 
 #[derive(Serialize)]
 struct VmInfo {
     vm_id: usize,
     status: Box<str>,
+    vmm_state: String,
+    guest_reachable: Option<bool>,
+    ready: bool,
 }
 
-async fn create_vm(headers: HeaderMap, vm_vec: Arc<Mutex<Vec<VmStatus>>>) -> Json<serde_json::Value> {
+async fn create_vm(headers: HeaderMap, body: String, vm_vec: Arc<Mutex<Vec<VmStatus>>>, 
+                    operations: OperationList, 
+                    pci_ledger: Arc<Mutex<PciLedger>>) -> Response {
     // Extract variable
     let image = headers.get("image").unwrap().to_str().unwrap();
-    let cpu = headers.get("cpu").unwrap().to_str().unwrap().parse::<i32>().unwrap();
-    let ram = headers.get("ram").unwrap().to_str().unwrap().parse::<i32>().unwrap();
+    let cpu = match headers.get("cpu").and_then(|value| value.to_str().ok()).unwrap_or("").parse::<i32>() {
+        Ok(cpu) => cpu,
+        Err(_) => return Json(json!({"Error": "cpu must be a number"})).into_response(),
+    };
+    let ram = match headers.get("ram").and_then(|value| value.to_str().ok()).unwrap_or("").parse::<i32>() {
+        Ok(ram) => ram,
+        Err(_) => return Json(json!({"Error": "ram must be a number"})).into_response(),
+    };
     let storage = headers.get("storage").unwrap().to_str().unwrap();
     let username = headers.get("username").unwrap().to_str().unwrap();
     let password = headers.get("password").unwrap().to_str().unwrap();
+    let name = headers.get("name").and_then(|value| value.to_str().ok());
+    let fqdn = headers.get("fqdn").and_then(|value| value.to_str().ok());
+    let pool = headers.get("pool").and_then(|value| value.to_str().ok()).unwrap_or(DEFAULT_POOL);
+    let project = headers.get("project").and_then(|value| value.to_str().ok());
+    let wait_ready = headers.get("wait-ready").and_then(|value| value.to_str().ok());
+
+    // Optional body carrying the user-data, vendor-data and custom meta-data
+    let payload: RequestVmData = if body.trim().is_empty() {
+        RequestVmData::default()
+    } else {
+        match serde_json::from_str(&body) {
+            Ok(payload) => payload,
+            Err(e) => return Json(json!({"Error": format!("Invalid request body: {}", e)})).into_response(),
+        }
+    };
+
+    info!("Validating the cloud-init data");
+    if let Some(user_data) = payload.user_data.as_deref() {
+        if let Err(e) = validate_cloud_data(user_data) {
+            return Json(json!({"Error": format!("Invalid user_data: {}", e)})).into_response();
+        }
+    }
+    if let Some(vendor_data) = payload.vendor_data.as_deref() {
+        if let Err(e) = validate_cloud_data(vendor_data) {
+            return Json(json!({"Error": format!("Invalid vendor_data: {}", e)})).into_response();
+        }
+    }
+
+    info!("Validating the disk rate limit");
+    let rate_limit = match payload.rate_limit.as_ref().map(parse_rate_limit).transpose() {
+        Ok(rate_limit) => rate_limit,
+        Err(e) => return Json(json!({"Error": format!("Invalid rate_limit: {}", e)})).into_response(),
+    };
+
+    info!("Validating the requested resources");
+    if cpu <= 0 || ram <= 0 {
+        return Json(json!({"Error": format!("Invalid resources: cpu {} and ram {} must be positive", cpu, ram)}))
+            .into_response();
+    }
+
+    info!("Validating the wait for ready");
+    let wait_ready = match wait_ready.map(|value| value.trim().parse::<u64>()).transpose() {
+        Ok(Some(seconds)) if seconds > MAX_WAIT_READY => {
+            return Json(json!({"Error": format!("Invalid wait-ready: at most {} seconds", MAX_WAIT_READY)}))
+                .into_response();
+        }
+        Ok(seconds) => seconds.filter(|seconds| *seconds > 0).map(Duration::from_secs),
+        Err(e) => return Json(json!({"Error": format!("Invalid wait-ready: {}", e)})).into_response(),
+    };
+
+    info!("Validating the storage size");
+    let storage_bytes = match parse_size(storage) {
+        Ok(bytes) => bytes,
+        Err(e) => return Json(json!({"Error": format!("Invalid storage: {}", e)})).into_response(),
+    };
+
+    info!("Validating the storage pool");
+    let pool = match find_pool(pool) {
+        Ok(pool) => pool,
+        Err(e) => return Json(json!({"Error": e})).into_response(),
+    };
+    if let Some(project) = project {
+        if validate_hostname(project).is_err() {
+            return Json(json!({"Error": format!("Invalid project: '{}'", project)})).into_response();
+        }
+    }
+
+    info!("Validating the meta-data");
+    if let Some(name) = name {
+        if let Err(e) = validate_hostname(name) {
+            return Json(json!({"Error": format!("Invalid name: {}", e)})).into_response();
+        }
+    }
+    if let Some(fqdn) = fqdn {
+        if let Err(e) = validate_fqdn(fqdn) {
+            return Json(json!({"Error": format!("Invalid fqdn: {}", e)})).into_response();
+        }
+    }
+    if let Err(e) = validate_metadata(&payload.metadata) {
+        return Json(json!({"Error": format!("Invalid metadata: {}", e)})).into_response();
+    }
+
+    info!("Validating the guest probes");
+    if let Err(e) = validate_probes(&payload.probes) {
+        return Json(json!({"Error": format!("Invalid probes: {}", e)})).into_response();
+    }
+
+    info!("Validating the boot devices");
+    let devices = match build_boot_devices(&payload.devices) {
+        Ok(devices) => devices,
+        Err(e) => return Json(json!({"Error": format!("Invalid devices: {}", e)})).into_response(),
+    };
+
+    // Resolved before the admission lock, an uncached image needs a request to its server
+    info!("Resolving the image size");
+    let image_url = image.to_string();
+    let (image_size, image_size_exact) = task::spawn_blocking(move || get_image_size(&image_url)).await
+        .unwrap_or((0, false));
+
+    // Hold the admission lock until the spec is on disk so parallel requests see each other
+    info!("Checking the host capacity");
+    let _admission = ADMISSION_LOCK.lock().unwrap();
+    if let Err(e) = check_admission(cpu, ram, &pci_ledger) {
+        warn!("{}", e);
+        return (StatusCode::CONFLICT, Json(json!({"Error": e}))).into_response();
+    }
 
     let vm_id = find_free_slot(&vm_vec);
+    Span::current().record("vm_id", vm_id);
     if vm_id < 0 {
-        return Json(json!({"Error": vm_id}));
+        return Json(json!({"Error": vm_id})).into_response();
+    }
+
+    if let Err(e) = reserve_boot_devices(vm_id, &devices, &pci_ledger) {
+        vm_vec.lock().unwrap()[vm_id as usize].status = -1;
+        return Json(json!({"Error": e})).into_response();
     }
 
-    println!("\nCreating config directory..");
+    // Checked before the download, the converted image lands in the pool next to the extra storage
+    info!("Checking the storage quota");
+    if let Err((code, e)) = check_storage(vm_id, project, &pool, image_size + storage_bytes) {
+        warn!("{}", e);
+        release_vm_pci_devices(vm_id, &pci_ledger, false);
+        vm_vec.lock().unwrap()[vm_id as usize].status = -1;
+        return (StatusCode::from_u16(code).unwrap_or(StatusCode::CONFLICT), Json(json!({"Error": e})))
+            .into_response();
+    }
+
+    let mut spec = VmSpec {
+        vm_id,
+        uuid: Uuid::new_v4().to_string(),
+        name: name.map(|name| name.to_string()).unwrap_or_else(|| format!("vm-{}", vm_id)),
+        fqdn: fqdn.map(|fqdn| fqdn.to_string()),
+        metadata: payload.metadata,
+        seed_generation: 0,
+        pool: pool.name.clone(),
+        project: project.map(|project| project.to_string()),
+        image: image.to_string(),
+        cpu,
+        ram,
+        storage: storage.to_string(),
+        username: username.to_string(),
+        password: password.to_string(),
+        user_data: payload.user_data,
+        vendor_data: payload.vendor_data,
+        disks: Vec::new(),
+        devices,
+        mdevs: Vec::new(),
+        probes: payload.probes,
+        host_drivers: BTreeMap::new(),
+    };
+
+    info!("Creating config directory");
     let config_path = format!("../vms-config/{}", vm_id);
     let _ = fs::create_dir_all(config_path.clone());
+    {
+        let _guard = lock_vm_spec(vm_id);
+        let _ = save_vm_spec(&config_path, &spec);
+    }
+    drop(_admission);
+    let uuid = spec.uuid.clone();
+    let name = spec.name.clone();
+
+    // The download size only bounds the image from below, check again with its virtual size
+    if !image_size_exact {
+        info!("Downloading the cloud image");
+        fetch_cloud_image(image);
+        let (image_size, _) = get_image_size(image);
+        if let Err((code, e)) = check_storage(vm_id, project, &pool, image_size + storage_bytes) {
+            warn!("{}", e);
+            delete_vm(&vm_vec, &pci_ledger, vm_id);
+            vm_vec.lock().unwrap()[vm_id as usize].status = -1;
+            return (StatusCode::from_u16(code).unwrap_or(StatusCode::CONFLICT), Json(json!({"Error": e})))
+                .into_response();
+        }
+    }
+
+    let disk_dir = vm_disk_dir(&pool, vm_id);
+    let _ = fs::create_dir_all(disk_dir.clone());
+
+    info!("Downloading the cloud image");
+    get_cloud_image(&disk_dir, image);
 
-    println!("\nDownloading the cloud image..");
-    get_cloud_image(&config_path, &image);
+    spec.disks = default_disks(&disk_dir, image);
+    spec.disks[0].rate_limit = rate_limit;
 
-    println!("\nWriting the VM starting config..");
+    info!("Writing the VM starting config");
     let ip_gw = format!("192.168.{}.1", vm_id);
     let ip = format!("192.168.{}.2", vm_id);
-    let _ = write_vm_config(vm_id, &config_path, &image, cpu, ram);
+    let _ = write_vm_config(&config_path, &spec);
     let _ = write_cloud_config(vm_id, &config_path);
-    let _ = create_cloud_init_files(&config_path, &username, &password, &ip, &ip_gw);
-    resize_storage(&config_path, &image, &storage);
+    let _ = create_cloud_init_files(&config_path, &spec, &ip, &ip_gw);
+    resize_storage(&disk_dir, image, &storage_bytes.to_string());
+    for disk in spec.disks.iter_mut() {
+        disk.size = get_disk_size(&disk.path);
+    }
+    {
+        let _guard = lock_vm_spec(vm_id);
+        let _ = save_vm_spec(&config_path, &spec);
+    }
 
+    let operation_id = create_operation("create", vm_id, &operations);
+    let operation_id_cloned = operation_id.clone();
+    let vm_vec_cloned = Arc::clone(&vm_vec);
+    let operations_cloned = Arc::clone(&operations);
+    let span = info_span!("operation", operation_id = %operation_id);
     thread::spawn(move || {
-        println!("\nRunning the VM..");
+        let _entered = span.enter();
+        info!("Running the VM");
+        start_operation(&operation_id, &operations);
         let cloud_status = run_cloud_init(&config_path);
-        let vm_status = start_vm(&vm_vec, vm_id, &config_path);
-        if vm_status != 1 && cloud_status != 1 {
-            println!("\nError: Cannot boot the VM.");
-        } 
-
-        {
-            let vm_vec = &mut vm_vec.lock().unwrap();
-            vm_vec[vm_id as usize].status = 0;
+        if cloud_status != 1 {
+            push_operation_error(&operation_id, "Cannot build the cloud-init seed image", &operations);
+        }
+        if run_vm_operation(&vm_vec, &pci_ledger, &operations, vm_id, &operation_id).is_err() {
+            // The VM never ran, its boot devices go back to the host
+            release_vm_pci_devices(vm_id, &pci_ledger, false);
         }
-        
-        return
     });
-    
-    Json(json!({
+
+    let mut response = json!({
         "vm_id": vm_id,
-    }))
+        "uuid": uuid,
+        "name": name,
+        "operation_id": operation_id_cloned,
+    });
+    let Some(wait_ready) = wait_ready else {
+        return Json(response).into_response();
+    };
+
+    info!("Waiting for the VM to be ready");
+    match wait_vm_ready(&vm_vec_cloned, &operations_cloned, &operation_id_cloned, vm_id, wait_ready).await {
+        Ok(ready_at) => {
+            response["ready"] = json!(true);
+            response["ready_at"] = json!(ready_at);
+            Json(response).into_response()
+        }
+        Err((code, e)) => {
+            warn!("{}", e);
+            response["ready"] = json!(false);
+            response["Error"] = json!(e);
+            let status = StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            (status, Json(response)).into_response()
+        }
+    }
 }
 
 async fn get_vms_info(vm_vec: Arc<Mutex<Vec<VmStatus>>>) -> impl IntoResponse{
@@ -84,6 +327,9 @@ async fn get_vms_info(vm_vec: Arc<Mutex<Vec<VmStatus>>>) -> impl IntoResponse{
             vm_info_list.push(VmInfo {
                 vm_id,
                 status: vm_status.into(),
+                vmm_state: vm_vec[vm_id].health.vmm_state.clone(),
+                guest_reachable: vm_vec[vm_id].health.guest_reachable,
+                ready: vm_vec[vm_id].ready_at.is_some(),
             });
         }
     }
@@ -95,7 +341,7 @@ async fn get_vm_status(vm_vec: Arc<Mutex<Vec<VmStatus>>>,
                         Path(vm_id): Path<String>) -> Json<serde_json::Value> {
     let vm_vec = vm_vec.lock().unwrap();
 
-    println!("\nValidating the vm id..");
+    info!("Validating the vm id");
     let vm_id: usize = match vm_id.parse() {
         Ok(id) => id,
         Err(_) => return 
@@ -105,30 +351,48 @@ async fn get_vm_status(vm_vec: Arc<Mutex<Vec<VmStatus>>>,
             })),
     };
     
-    println!("\nGetting the VM status..");
+    info!("Getting the VM status");
     let mut vm_status = "Not Found";
     if vm_vec[vm_id].status >= 0 {
         vm_status = STATUS[vm_vec[vm_id].status as usize];
     }   
 
+    // Disks with their configured rate limits and the boot devices
+    let spec = load_vm_spec(&format!("../vms-config/{}", vm_id)).unwrap_or_default();
+
     Json(json!({
         "vm_id": vm_id,
         "status": vm_status,
+        "vmm_state": vm_vec[vm_id].health.vmm_state,
+        "guest_reachable": vm_vec[vm_id].health.guest_reachable,
+        "checked_at": vm_vec[vm_id].health.checked_at,
+        "probe_latency_ms": vm_vec[vm_id].health.probe_latency_ms,
+        "ready": vm_vec[vm_id].ready_at.is_some(),
+        "ready_at": vm_vec[vm_id].ready_at,
+        "probes": spec.probes,
+        "disks": spec.disks,
+        "devices": spec.devices,
     }))
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
+    init_logging();
+
     // Init data structure
     let vm_vec: Arc<Mutex<Vec<VmStatus>>> = Arc::new(Mutex::new(Vec::with_capacity(MAXVM)));
     init_vm_vec(&vm_vec);
-    let ticket_list: Arc<Mutex<LinkedList<Ticket>>> = Arc::new(Mutex::new(LinkedList::new()));
+    let operations: OperationList = Arc::new(Mutex::new(HashMap::new()));
+    let pci_ledger: Arc<Mutex<PciLedger>> = Arc::new(Mutex::new(load_pci_ledger()));
+    let monitor_stats: Arc<Mutex<MonitorStats>> = Arc::new(Mutex::new(MonitorStats::default()));
+    let metrics: MetricsState = new_metrics();
 
     // Spawn monitoring as a task
-    let _ = tokio::spawn({
+    tokio::spawn({
         let vm_vec_clone = Arc::clone(&vm_vec);
+        let monitor_stats_clone = Arc::clone(&monitor_stats);
         async move {
-            monitor_vms(&vm_vec_clone).await;
+            monitor_vms(&vm_vec_clone, &monitor_stats_clone).await;
         }
     });
 
@@ -138,13 +402,32 @@ async fn main() {
     let binding = vmm_str.clone() + "/{vm_id}/config";
     let vm_config_str = binding.as_str();
     let pci_str = format!("/api/v1/nodes/{}/vmm/hardware/pci", node_name);
+    let gpus_str = format!("/api/v1/nodes/{}/vmm/hardware/gpus", node_name);
+    let sriov_str = format!("/api/v1/nodes/{}/vmm/hardware/sriov", node_name);
+    let mdevs_str = format!("/api/v1/nodes/{}/vmm/hardware/mdevs", node_name);
+    let pools_str = format!("/api/v1/nodes/{}/pools", node_name);
+    let quotas_str = format!("/api/v1/nodes/{}/quotas", node_name);
+    let operations_str = format!("/api/v1/nodes/{}/operations", node_name);
+    let monitor_str = format!("/api/v1/nodes/{}/monitor", node_name);
+    let node_str = format!("/api/v1/nodes/{}", node_name);
     let app = Router::new()
+        // Host capacity
+        .route(
+            node_str.as_str(),
+            get({
+                let pci_ledger = Arc::clone(&pci_ledger);
+                let node_name = node_name.to_string();
+                move || filter_host_info(Path(node_name), pci_ledger)
+            }),
+        )
         // Create and get status VMM
         .route(
             vmm_str.as_str(),
             post({
                 let vm_vec = Arc::clone(&vm_vec);
-                move |headers| create_vm(headers, vm_vec)
+                let operations = Arc::clone(&operations);
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |headers, body| create_vm(headers, body, vm_vec, operations, pci_ledger)
             }),
         )
         .route(
@@ -166,21 +449,26 @@ async fn main() {
             (vmm_str.clone() + "/{vm_id}/start").as_str(),
             post({
                 let vm_vec = Arc::clone(&vm_vec);
-                move |path| filter_start_vm(vm_vec, path)
+                let pci_ledger = Arc::clone(&pci_ledger);
+                let operations = Arc::clone(&operations);
+                move |path| filter_start_vm(vm_vec, pci_ledger, operations, path)
             }),
         )
         .route(
             (vmm_str.clone() + "/{vm_id}/stop").as_str(),
             post({
                 let vm_vec = Arc::clone(&vm_vec);
-                move |path| filter_stop_vm(vm_vec, path)
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |path| filter_stop_vm(vm_vec, pci_ledger, path)
             }),
         )
         .route(
             (vmm_str.clone() + "/{vm_id}/reboot").as_str(),
             post({
                 let vm_vec = Arc::clone(&vm_vec);
-                move |path| filter_reboot_vm(vm_vec, path)
+                let pci_ledger = Arc::clone(&pci_ledger);
+                let operations = Arc::clone(&operations);
+                move |path| filter_reboot_vm(vm_vec, pci_ledger, operations, path)
             }),
         )
         .route(
@@ -194,47 +482,202 @@ async fn main() {
             (vmm_str.clone() + "/{vm_id}/delete").as_str(),
             post({
                 let vm_vec = Arc::clone(&vm_vec);
-                move |path| filter_delete_vm(vm_vec, path)
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |path| filter_delete_vm(vm_vec, pci_ledger, path)
+            }),
+        )
+        .route(
+            monitor_str.as_str(),
+            get({
+                let vm_vec = Arc::clone(&vm_vec);
+                let monitor_stats = Arc::clone(&monitor_stats);
+                move || filter_monitor_status(vm_vec, monitor_stats)
+            }),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/stats").as_str(),
+            get({
+                let vm_vec = Arc::clone(&vm_vec);
+                move |path, query| filter_vm_stats(vm_vec, path, query)
+            }),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/console").as_str(),
+            get(filter_console),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/console/log").as_str(),
+            get(filter_console_log),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/probes").as_str(),
+            put(filter_set_probes),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/rebuild_seed").as_str(),
+            post(filter_rebuild_seed),
+        )
+        // Storage
+        .route(
+            (vmm_str.clone() + "/{vm_id}/disks/{disk}/size").as_str(),
+            put({
+                let vm_vec = Arc::clone(&vm_vec);
+                move |path, json_data| filter_resize_disk(vm_vec, path, json_data)
+            }),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/disks").as_str(),
+            post({
+                let vm_vec = Arc::clone(&vm_vec);
+                move |path, json_data| filter_attach_disk(vm_vec, path, json_data)
+            }),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/disks/{disk}/rate_limit").as_str(),
+            put({
+                let vm_vec = Arc::clone(&vm_vec);
+                move |path, json_data| filter_set_disk_rate_limit(vm_vec, path, json_data)
+            }),
+        )
+        .route(
+            pools_str.as_str(),
+            get(filter_list_pools).post(filter_add_pool),
+        )
+        .route(
+            (pools_str.clone() + "/{pool}").as_str(),
+            get(filter_get_pool).delete(filter_remove_pool),
+        )
+        .route(
+            quotas_str.as_str(),
+            get(filter_get_quotas).put(filter_set_quotas),
+        )
+        // Long running operations
+        .route(
+            (operations_str.clone() + "/{operation_id}").as_str(),
+            get({
+                let operations = Arc::clone(&operations);
+                move |path| filter_get_operation(path, operations)
             }),
         )
         // Hardware
         .route(
             vm_config_str,
-            get(move |path| filter_get_vm_config(path)),
+            get(filter_get_vm_config),
         )
         .route(
             vm_config_str,
             put({
-                let ticket_list = Arc::clone(&ticket_list);
-                move |path, json_data| filter_add_pci(path, json_data, ticket_list)
+                let operations = Arc::clone(&operations);
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |path, json_data| filter_add_pci(path, json_data, operations, pci_ledger)
             }),
         )
         .route(
             vm_config_str,
             delete({
-                move |path, json_data| filter_remove_pci(path, json_data)
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |path, json_data| filter_remove_pci(path, json_data, pci_ledger)
             }),
         )
         .route(
             pci_str.as_str(),
-            get( filter_pcis_info(&"", &"").await ),
+            get({
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |query| filter_pcis_info(query, pci_ledger)
+            }),
+        )
+        .route(
+            gpus_str.as_str(),
+            get({
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move || filter_gpus_info(pci_ledger)
+            }),
+        )
+        .route(
+            sriov_str.as_str(),
+            get({
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move || filter_sriov_info(pci_ledger)
+            }),
+        )
+        .route(
+            (sriov_str.clone() + "/{address}").as_str(),
+            put({
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |path, json_data| filter_set_num_vfs(path, json_data, pci_ledger)
+            }),
+        )
+        .route(
+            (sriov_str.clone() + "/{address}/vfs/{index}").as_str(),
+            put(filter_set_vf_config),
+        )
+        .route(
+            (vmm_str.clone() + "/hardware/mdev_types").as_str(),
+            get(filter_mdev_types),
+        )
+        .route(
+            mdevs_str.as_str(),
+            get(filter_mdevs_info).post(filter_create_mdev),
+        )
+        .route(
+            (mdevs_str.clone() + "/{uuid}").as_str(),
+            delete(filter_remove_mdev),
         )
         .route(
             (vmm_str.clone() + "/{vm_id}/pt_status").as_str(),
             get({
-                let ticket_list = Arc::clone(&ticket_list);
-                move |headers| filter_pt_status(headers, ticket_list)
+                let operations = Arc::clone(&operations);
+                move |headers| filter_pt_status(headers, operations)
             }),
         )
         .route(
             (vmm_str.clone() + "/{vm_id}/gpus").as_str(),
             put({
-                let ticket_list = Arc::clone(&ticket_list);
-                move |path, json_data| filter_add_gpu(path, json_data, ticket_list)
+                let operations = Arc::clone(&operations);
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |path, json_data| filter_add_gpu(path, json_data, operations, pci_ledger)
+            }),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/vfs").as_str(),
+            put({
+                let operations = Arc::clone(&operations);
+                let pci_ledger = Arc::clone(&pci_ledger);
+                move |path, json_data| filter_add_vf(path, json_data, operations, pci_ledger)
+            }),
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/mdevs").as_str(),
+            put({
+                let vm_vec = Arc::clone(&vm_vec);
+                move |path, json_data| filter_attach_mdev(path, json_data, vm_vec)
             }),
-        );
+        )
+        .route(
+            (vmm_str.clone() + "/{vm_id}/mdevs/{uuid}").as_str(),
+            delete({
+                let vm_vec = Arc::clone(&vm_vec);
+                move |path| filter_detach_mdev(path, vm_vec)
+            }),
+        )
+        // Metrics
+        .route(
+            "/metrics",
+            get({
+                let metrics = Arc::clone(&metrics);
+                let vm_vec = Arc::clone(&vm_vec);
+                let monitor_stats = Arc::clone(&monitor_stats);
+                move || filter_metrics(metrics, vm_vec, monitor_stats)
+            }),
+        )
+        .layer(middleware::from_fn({
+            let metrics = Arc::clone(&metrics);
+            move |request, next| track_requests(Arc::clone(&metrics), request, next)
+        }))
+        .layer(middleware::from_fn(trace_requests));
 
     // Run server
     let listener = tokio::net::TcpListener::bind("0.0.0.0:2546").await.unwrap();
+    info!("Listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app).await.unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,506 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_yaml::{Mapping, Value};
+use tracing::{info};
+
+use crate::main_lib::structure::VmSpec;
+use crate::main_lib::console::READY_MARKER;
+
+const MIME_BOUNDARY: &str = "==CHV-CONTROLLER-BOUNDARY==";
+
+// Part types cloud-init knows how to handle inside a multipart archive
+const MIME_PART_TYPES: [&str; 8] = ["text/cloud-config", "text/x-shellscript", "text/cloud-boothook",
+                                    "text/x-include-url", "text/part-handler", "text/jinja2",
+                                    "text/cloud-config-archive", "text/x-shellscript-per-boot"];
+
+// Keys the controller always owns in the generated user-data and meta-data
+const RESERVED_KEYS: [&str; 1] = ["network"];
+const RESERVED_META_KEYS: [&str; 2] = ["instance-id", "local-hostname"];
+
+pub fn controller_user_data(username: &str, password: &str) -> String {
+    format!(
+        "#cloud-config
+users:
+  - name: {}
+    passwd: {}
+    sudo: ALL=(ALL) NOPASSWD:ALL
+    lock_passwd: False
+    inactive: False
+    shell: /bin/bash
+
+ssh_pwauth: True
+
+runcmd:
+  - [ systemctl, daemon-reload ]
+  - [ systemctl, enable, chv-ready.service ]
+  - [ systemctl, start, --no-block, chv-ready.service ]
+
+write_files:
+  - path: /etc/systemd/system/chv-ready.service
+    permissions: '0644'
+    content: |
+      [Unit]
+      Description=Report on the serial console that the guest finished booting
+      After=cloud-final.service
+
+      [Service]
+      Type=oneshot
+      ExecStart=/bin/sh -c 'echo {} > /dev/ttyS0'
+
+      [Install]
+      WantedBy=multi-user.target
+",
+        username, password, READY_MARKER
+    )
+}
+
+fn is_cloud_config(data: &str) -> bool {
+    data.trim_start().starts_with("#cloud-config")
+}
+
+fn is_multipart(data: &str) -> bool {
+    find_boundary(data).is_some()
+}
+
+fn parse_cloud_config(data: &str) -> Result<Mapping, String> {
+    match serde_yaml::from_str::<Value>(data) {
+        Ok(Value::Mapping(mapping)) => Ok(mapping),
+        Ok(Value::Null) => Ok(Mapping::new()),
+        Ok(_) => Err("cloud-config must be a YAML mapping".to_string()),
+        Err(e) => Err(format!("invalid YAML: {}", e)),
+    }
+}
+
+fn render_cloud_config(mapping: &Mapping) -> Result<String, String> {
+    match serde_yaml::to_string(mapping) {
+        Ok(body) => Ok(format!("#cloud-config\n{}", body)),
+        Err(e) => Err(format!("cannot serialize cloud-config: {}", e)),
+    }
+}
+
+fn strip_reserved_keys(mapping: &mut Mapping) {
+    for key in RESERVED_KEYS {
+        if mapping.remove(key).is_some() {
+            info!("Ignoring the user supplied '{}' key, it is managed by the controller", key);
+        }
+    }
+}
+
+// Make sure the controller user is present without dropping the users supplied by the caller
+fn merge_users(mapping: &mut Mapping, controller: &Mapping) {
+    let controller_users = match controller.get("users") {
+        Some(Value::Sequence(users)) => users.clone(),
+        _ => return,
+    };
+
+    let mut users = match mapping.remove("users") {
+        Some(Value::Sequence(users)) => users,
+        _ => Vec::new(),
+    };
+
+    for controller_user in controller_users {
+        let name = controller_user.get("name").cloned();
+        let exists = users.iter().any(|user| user.get("name").cloned() == name);
+        if !exists {
+            users.push(controller_user);
+        }
+    }
+
+    mapping.insert(Value::from("users"), Value::Sequence(users));
+}
+
+// Append the controller entries of a list such as runcmd after the ones supplied by the caller
+fn merge_list(mapping: &mut Mapping, controller: &Mapping, key: &str) {
+    let controller_items = match controller.get(key) {
+        Some(Value::Sequence(items)) => items.clone(),
+        _ => return,
+    };
+
+    let mut items = match mapping.remove(key) {
+        Some(Value::Sequence(items)) => items,
+        _ => Vec::new(),
+    };
+    for item in controller_items {
+        if !items.contains(&item) {
+            items.push(item);
+        }
+    }
+
+    mapping.insert(Value::from(key), Value::Sequence(items));
+}
+
+fn merge_cloud_config(controller: &str, user_data: &str) -> Result<String, String> {
+    let controller = parse_cloud_config(controller)?;
+    let mut mapping = parse_cloud_config(user_data)?;
+    strip_reserved_keys(&mut mapping);
+    merge_users(&mut mapping, &controller);
+    merge_list(&mut mapping, &controller, "runcmd");
+    merge_list(&mut mapping, &controller, "write_files");
+
+    // Keep the controller defaults for anything the caller did not set
+    for (key, value) in controller.iter() {
+        if !mapping.contains_key(key) {
+            mapping.insert(key.clone(), value.clone());
+        }
+    }
+
+    render_cloud_config(&mapping)
+}
+
+fn find_boundary(data: &str) -> Option<String> {
+    for line in data.lines() {
+        if line.trim().is_empty() {
+            break;
+        }
+        let lower = line.to_ascii_lowercase();
+        if !lower.starts_with("content-type:") || !lower.contains("multipart/") {
+            continue;
+        }
+        for param in line.split(';').skip(1) {
+            let param = param.trim();
+            if param.to_ascii_lowercase().starts_with("boundary=") {
+                let boundary = param["boundary=".len()..].trim_matches('"');
+                if !boundary.is_empty() {
+                    return Some(boundary.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn split_headers(part: &str) -> (&str, &str) {
+    match part.find("\n\n") {
+        Some(index) => (&part[..index], &part[index + 2..]),
+        None => (part, ""),
+    }
+}
+
+fn part_content_type(headers: &str) -> String {
+    headers.lines()
+        .find(|line| line.to_ascii_lowercase().starts_with("content-type:"))
+        .map(|line| line["content-type:".len()..].split(';').next().unwrap_or("")
+                        .trim().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+fn part_transfer_encoding(headers: &str) -> String {
+    headers.lines()
+        .find(|line| line.to_ascii_lowercase().starts_with("content-transfer-encoding:"))
+        .map(|line| line["content-transfer-encoding:".len()..].trim().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+// Decode a base64 part, the headers lose their transfer encoding since the body is re-rendered as text
+fn decode_part(headers: &str, body: &str) -> Result<(String, String), String> {
+    if part_transfer_encoding(headers) != "base64" {
+        return Ok((headers.to_string(), body.to_string()));
+    }
+    let encoded: String = body.split_whitespace().collect();
+    let decoded = STANDARD.decode(encoded).map_err(|e| format!("invalid base64 body: {}", e))?;
+    let body = String::from_utf8(decoded).map_err(|_| "the decoded body is not UTF-8".to_string())?;
+    let headers = headers.lines()
+        .filter(|line| !line.to_ascii_lowercase().starts_with("content-transfer-encoding:"))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    Ok((headers, body))
+}
+
+// Split a multipart document into its raw parts (headers and body)
+fn split_multipart(data: &str) -> Result<Vec<String>, String> {
+    let boundary = find_boundary(data).ok_or("missing multipart boundary")?;
+    let data = data.replace("\r\n", "\n");
+    let delimiter = format!("--{}", boundary);
+    let closing = format!("--{}--", boundary);
+
+    let mut parts = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    let mut closed = false;
+    for line in data.lines() {
+        if line.trim_end() == closing {
+            if let Some(lines) = current.take() {
+                parts.push(lines.join("\n"));
+            }
+            closed = true;
+            break;
+        }
+        if line.trim_end() == delimiter {
+            if let Some(lines) = current.take() {
+                parts.push(lines.join("\n"));
+            }
+            current = Some(Vec::new());
+            continue;
+        }
+        if let Some(lines) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+
+    if !closed {
+        return Err(format!("multipart document is missing the closing boundary {}", closing));
+    }
+    if parts.is_empty() {
+        return Err("multipart document has no parts".to_string());
+    }
+    Ok(parts)
+}
+
+// Validate every part and drop the reserved keys from the cloud-config ones
+fn sanitize_multipart(data: &str) -> Result<Vec<String>, String> {
+    let mut parts = Vec::new();
+    for (index, part) in split_multipart(data)?.iter().enumerate() {
+        let (headers, body) = split_headers(part);
+        let content_type = part_content_type(headers);
+        if !MIME_PART_TYPES.contains(&content_type.as_str()) {
+            return Err(format!("part {} has an unsupported content type '{}'", index, content_type));
+        }
+
+        if content_type == "text/cloud-config" {
+            let (headers, body) = decode_part(headers, body)
+                .map_err(|e| format!("part {}: {}", index, e))?;
+            let mut mapping = parse_cloud_config(&body)
+                .map_err(|e| format!("part {}: {}", index, e))?;
+            strip_reserved_keys(&mut mapping);
+            parts.push(format!("{}\n\n{}", headers, render_cloud_config(&mapping)?));
+        } else {
+            parts.push(part.to_string());
+        }
+    }
+    Ok(parts)
+}
+
+fn render_multipart(parts: &[String]) -> String {
+    let mut content = format!("Content-Type: multipart/mixed; boundary=\"{}\"\n\
+                               MIME-Version: 1.0\n\n", MIME_BOUNDARY);
+    for part in parts {
+        content.push_str(&format!("--{}\n{}\n", MIME_BOUNDARY, part.trim_end()));
+    }
+    content.push_str(&format!("--{}--\n", MIME_BOUNDARY));
+    content
+}
+
+// Check that the supplied data is something cloud-init will accept
+pub fn validate_cloud_data(data: &str) -> Result<(), String> {
+    if is_cloud_config(data) {
+        parse_cloud_config(data).map(|_| ())
+    } else if is_multipart(data) {
+        sanitize_multipart(data).map(|_| ())
+    } else {
+        Err("expected a '#cloud-config' document or a multipart MIME archive".to_string())
+    }
+}
+
+// Build the final user-data by merging the caller's data with the controller's required keys
+pub fn build_user_data(username: &str, password: &str, user_data: Option<&str>)
+                        -> Result<String, String> {
+    let controller = controller_user_data(username, password);
+    let user_data = match user_data {
+        Some(data) if !data.trim().is_empty() => data,
+        _ => return Ok(controller),
+    };
+
+    if is_cloud_config(user_data) {
+        return merge_cloud_config(&controller, user_data);
+    }
+
+    if is_multipart(user_data) {
+        // cloud-init replaces lists between parts by default, the merge settings of a part decide
+        // how it merges into the parts before it. The controller part goes last with list append
+        // so its users, runcmd and write_files (chv-ready.service) are added to the caller's ones.
+        // The settings are given both as the Merge-Type header and the merge_how key
+        let mut parts = sanitize_multipart(user_data)?;
+        parts.push(format!(
+            "Content-Type: text/cloud-config; charset=\"us-ascii\"
+MIME-Version: 1.0
+Content-Disposition: attachment; filename=\"controller.cfg\"
+Merge-Type: list(append)+dict(no_replace,recurse_list)+str()
+
+{}merge_how:
+  - name: list
+    settings: [append]
+  - name: dict
+    settings: [no_replace, recurse_list]
+",
+            controller
+        ));
+        return Ok(render_multipart(&parts));
+    }
+
+    Err("expected a '#cloud-config' document or a multipart MIME archive".to_string())
+}
+
+pub fn build_vendor_data(vendor_data: Option<&str>) -> Result<String, String> {
+    match vendor_data {
+        Some(data) if !data.trim().is_empty() => {
+            validate_cloud_data(data)?;
+            Ok(data.to_string())
+        }
+        _ => Ok(String::new()),
+    }
+}
+
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty() && label.len() <= 63
+        && !label.starts_with('-') && !label.ends_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub fn validate_hostname(name: &str) -> Result<(), String> {
+    if is_valid_label(name) {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a valid hostname", name))
+    }
+}
+
+pub fn validate_fqdn(fqdn: &str) -> Result<(), String> {
+    let fqdn = fqdn.trim_end_matches('.');
+    if fqdn.len() <= 253 && fqdn.contains('.') && fqdn.split('.').all(is_valid_label) {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a valid FQDN", fqdn))
+    }
+}
+
+pub fn validate_metadata(metadata: &serde_json::Map<String, serde_json::Value>) -> Result<(), String> {
+    for key in metadata.keys() {
+        if RESERVED_META_KEYS.contains(&key.as_str()) {
+            return Err(format!("'{}' is managed by the controller", key));
+        }
+    }
+    Ok(())
+}
+
+// A new generation gives a new instance-id so cloud-init runs again on the next boot
+pub fn instance_id(spec: &VmSpec) -> String {
+    if spec.seed_generation == 0 {
+        format!("iid-{}", spec.uuid)
+    } else {
+        format!("iid-{}-{}", spec.uuid, spec.seed_generation)
+    }
+}
+
+pub fn build_meta_data(spec: &VmSpec) -> Result<String, String> {
+    let mut mapping = Mapping::new();
+    mapping.insert(Value::from("instance-id"), Value::from(instance_id(spec)));
+
+    // cloud-init splits a dotted local-hostname into the hostname and the fqdn
+    let hostname = spec.fqdn.clone().unwrap_or_else(|| spec.name.clone());
+    mapping.insert(Value::from("local-hostname"), Value::from(hostname));
+
+    for (key, value) in spec.metadata.iter() {
+        if RESERVED_META_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        let value = serde_yaml::to_value(value)
+            .map_err(|e| format!("cannot convert metadata '{}': {}", key, e))?;
+        mapping.insert(Value::from(key.as_str()), value);
+    }
+
+    serde_yaml::to_string(&mapping).map_err(|e| format!("cannot serialize meta-data: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_names(data: &str) -> Vec<String> {
+        let mapping = parse_cloud_config(data).unwrap();
+        match mapping.get("users") {
+            Some(Value::Sequence(users)) => users.iter()
+                .filter_map(|user| user.get("name").and_then(|name| name.as_str()).map(str::to_string))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn merge_keeps_caller_users_and_adds_controller_user() {
+        let user_data = "#cloud-config\nusers:\n  - name: alice\npackages: [htop]\n";
+        let merged = build_user_data("cloud", "secret", Some(user_data)).unwrap();
+        assert_eq!(user_names(&merged), vec!["alice", "cloud"]);
+
+        let mapping = parse_cloud_config(&merged).unwrap();
+        assert!(mapping.contains_key("packages"));
+        assert_eq!(mapping.get("ssh_pwauth"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn merge_does_not_duplicate_controller_user() {
+        let user_data = "#cloud-config\nusers:\n  - name: cloud\n    shell: /bin/zsh\n";
+        let merged = build_user_data("cloud", "secret", Some(user_data)).unwrap();
+        assert_eq!(user_names(&merged), vec!["cloud"]);
+        assert!(merged.contains("/bin/zsh"));
+    }
+
+    #[test]
+    fn merge_strips_reserved_keys() {
+        let user_data = "#cloud-config\nnetwork:\n  version: 2\nhostname: guest\n";
+        let merged = build_user_data("cloud", "secret", Some(user_data)).unwrap();
+        let mapping = parse_cloud_config(&merged).unwrap();
+        assert!(!mapping.contains_key("network"));
+        assert!(mapping.contains_key("hostname"));
+    }
+
+    #[test]
+    fn merge_appends_controller_lists() {
+        let user_data = "#cloud-config\nruncmd:\n  - [ touch, /tmp/caller ]\n";
+        let merged = build_user_data("cloud", "secret", Some(user_data)).unwrap();
+        let mapping = parse_cloud_config(&merged).unwrap();
+        let runcmd = mapping.get("runcmd").and_then(|runcmd| runcmd.as_sequence()).unwrap();
+        assert_eq!(runcmd[0], serde_yaml::from_str::<Value>("[ touch, /tmp/caller ]").unwrap());
+        assert!(runcmd.len() > 1);
+        assert!(mapping.contains_key("write_files"));
+    }
+
+    #[test]
+    fn empty_user_data_returns_controller_data() {
+        let controller = controller_user_data("cloud", "secret");
+        assert_eq!(build_user_data("cloud", "secret", None).unwrap(), controller);
+        assert_eq!(build_user_data("cloud", "secret", Some("  \n")).unwrap(), controller);
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(validate_cloud_data("#!/bin/sh\necho hi\n").is_err());
+        assert!(validate_cloud_data("#cloud-config\n- not\n- a mapping\n").is_err());
+    }
+
+    #[test]
+    fn multipart_base64_cloud_config_is_decoded() {
+        let encoded = STANDARD.encode("#cloud-config\nnetwork:\n  version: 2\npackages: [htop]\n");
+        let user_data = format!("Content-Type: multipart/mixed; boundary=\"XYZ\"\nMIME-Version: 1.0\n\n\
+                                 --XYZ\nContent-Type: text/cloud-config\nContent-Transfer-Encoding: base64\n\n\
+                                 {}\n--XYZ--\n", encoded);
+        assert!(validate_cloud_data(&user_data).is_ok());
+
+        let merged = build_user_data("cloud", "secret", Some(&user_data)).unwrap();
+        assert!(merged.contains("packages"));
+        assert!(!merged.contains("network"));
+        assert!(!merged.to_ascii_lowercase().contains("content-transfer-encoding"));
+    }
+
+    #[test]
+    fn multipart_appends_the_controller_part() {
+        let user_data = "Content-Type: multipart/mixed; boundary=\"XYZ\"\nMIME-Version: 1.0\n\n\
+                         --XYZ\nContent-Type: text/cloud-config\n\n\
+                         #cloud-config\nruncmd:\n  - [ touch, /tmp/caller ]\n--XYZ--\n";
+        let merged = build_user_data("cloud", "secret", Some(user_data)).unwrap();
+        let parts = sanitize_multipart(&merged).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].contains("/tmp/caller"));
+
+        // The ready unit only survives the merge when the controller part appends its lists
+        let controller = &parts[1];
+        assert!(controller.contains("chv-ready.service"));
+        assert!(controller.contains("Merge-Type: list(append)+dict(no_replace,recurse_list)+str()"));
+        assert!(controller.contains("merge_how:"));
+    }
+
+    #[test]
+    fn multipart_rejects_invalid_base64() {
+        let user_data = "Content-Type: multipart/mixed; boundary=\"XYZ\"\nMIME-Version: 1.0\n\n\
+                         --XYZ\nContent-Type: text/cloud-config\nContent-Transfer-Encoding: base64\n\n\
+                         !!!not base64!!!\n--XYZ--\n";
+        assert!(validate_cloud_data(user_data).is_err());
+    }
+}
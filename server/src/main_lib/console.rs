@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    process::Command,
+    sync::{Arc, LazyLock, Mutex, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::Duration,
+};
+
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg};
+use nix::unistd::getuid;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tracing::{error, info, info_span, warn};
+
+use crate::main_lib::structure::VmStatus;
+use crate::main_lib::manage_vm::call_vmm_api;
+use crate::main_lib::health::unix_now;
+
+const SERIAL_LOG_NAME: &str = "serial.log";
+const SERIAL_LOG_MAX_SIZE: u64 = 1024 * 1024;
+// Rotated files are serial.log.1 (newest) up to serial.log.3 (oldest)
+const SERIAL_LOG_KEEP: usize = 3;
+const PTY_WAIT_ATTEMPTS: u32 = 30;
+const CONSOLE_CHANNEL_SIZE: usize = 256;
+// Printed on the serial console by the chv-ready service once cloud-init is done
+pub const READY_MARKER: &str = "CHV-GUEST-READY";
+const MAX_LINE_LENGTH: usize = 4096;
+
+// Live serial consoles, the capture thread of a VM registers it while the pty is open
+pub struct ConsoleHub {
+    pub output: broadcast::Sender<Vec<u8>>,
+    pub input: Arc<Mutex<File>>,
+    pub writer: Arc<AtomicBool>,
+}
+
+static CONSOLES: LazyLock<Mutex<HashMap<i16, ConsoleHub>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Releases the read-write slot of the console when the session ends
+pub struct WriterGuard(Arc<AtomicBool>);
+
+impl Drop for WriterGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+pub struct ConsoleSession {
+    pub output: broadcast::Receiver<Vec<u8>>,
+    // Only the read-write session gets the pty input
+    pub input: Option<(Arc<Mutex<File>>, WriterGuard)>,
+}
+
+// Join the console of a running VM, at most one session can write to it at a time
+pub fn open_console_session(vm_id: i16, read_write: bool) -> Result<ConsoleSession, (u16, String)> {
+    let consoles = CONSOLES.lock().unwrap();
+    let hub = consoles.get(&vm_id)
+        .ok_or((404, format!("The console of vm_id: {} is not available", vm_id)))?;
+
+    let input = if read_write {
+        if hub.writer.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err((409, format!("The console of vm_id: {} already has a read-write session", vm_id)));
+        }
+        Some((Arc::clone(&hub.input), WriterGuard(Arc::clone(&hub.writer))))
+    } else {
+        None
+    };
+    Ok(ConsoleSession { output: hub.output.subscribe(), input })
+}
+
+pub fn console_log_path(vm_id: i16) -> String {
+    format!("../vms-config/{}/{}", vm_id, SERIAL_LOG_NAME)
+}
+
+fn rotated_path(path: &str, index: usize) -> String {
+    format!("{}.{}", path, index)
+}
+
+// Append only log file that rolls over to numbered files once it reaches its maximum size
+pub struct RotatingLog {
+    path: String,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    pub fn open(path: &str) -> std::io::Result<RotatingLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingLog { path: path.to_string(), file, size })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..SERIAL_LOG_KEEP).rev() {
+            let _ = fs::rename(rotated_path(&self.path, index), rotated_path(&self.path, index + 1));
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if self.size + data.len() as u64 > SERIAL_LOG_MAX_SIZE && self.size > 0 {
+            self.rotate()?;
+        }
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+}
+
+// The hypervisor allocates the pty when the VM boots and reports it in vm.info
+fn get_serial_pty(vm_id: i16) -> Option<String> {
+    let api_socket = format!("/tmp/cloud-hypervisor{}.sock", vm_id);
+    for _ in 0..PTY_WAIT_ATTEMPTS {
+        if !Path::new(&api_socket).exists() {
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+        let serial = call_vmm_api(vm_id, "GET", "vm.info", None).ok()
+            .and_then(|info| serde_json::from_str::<Value>(&info).ok())
+            .map(|info| info["config"]["serial"].clone());
+        match serial {
+            Some(serial) if serial["mode"] == "Pty" => {
+                if let Some(file) = serial["file"].as_str() {
+                    return Some(file.to_string());
+                }
+            }
+            // The VM runs with another serial mode, nothing to capture
+            Some(serial) if serial.is_object() => return None,
+            _ => {}
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    None
+}
+
+// The pty belongs to root when the hypervisor runs through sudo
+pub fn open_pty(path: &str) -> Result<File, String> {
+    let open = || OpenOptions::new().read(true).write(true)
+        .custom_flags(nix::libc::O_NOCTTY)
+        .open(path);
+    let file = match open() {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let status = Command::new("sudo").arg("chown").arg(getuid().to_string()).arg(path).status();
+            if !matches!(status, Ok(status) if status.success()) {
+                return Err(format!("Cannot take the ownership of {}", path));
+            }
+            open().map_err(|e| format!("Cannot open {}: {}", path, e))?
+        }
+        Err(e) => return Err(format!("Cannot open {}: {}", path, e)),
+    };
+
+    // Without raw mode the line discipline would echo the guest output back to the guest
+    let mut termios = tcgetattr(&file).map_err(|e| format!("Cannot read the termios of {}: {}", path, e))?;
+    cfmakeraw(&mut termios);
+    tcsetattr(&file, SetArg::TCSANOW, &termios)
+        .map_err(|e| format!("Cannot set {} to raw mode: {}", path, e))?;
+    Ok(file)
+}
+
+fn capture_console(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) -> Result<(), String> {
+    let pty_path = match get_serial_pty(vm_id) {
+        Some(path) => path,
+        None => {
+            warn!("No serial pty reported, the console is not captured");
+            return Ok(());
+        }
+    };
+    let mut pty = open_pty(&pty_path)?;
+    let input = pty.try_clone().map_err(|e| format!("Cannot clone the pty: {}", e))?;
+    let mut log = RotatingLog::open(&console_log_path(vm_id))
+        .map_err(|e| format!("Cannot open the console log: {}", e))?;
+    info!("Capturing the serial console {}", pty_path);
+
+    let (output, _) = broadcast::channel(CONSOLE_CHANNEL_SIZE);
+    CONSOLES.lock().unwrap().insert(vm_id, ConsoleHub {
+        output: output.clone(),
+        input: Arc::new(Mutex::new(input)),
+        writer: Arc::new(AtomicBool::new(false)),
+    });
+
+    let mut buffer = [0u8; 4096];
+    let mut line: Vec<u8> = Vec::new();
+    loop {
+        match pty.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => {
+                for byte in &buffer[..count] {
+                    if *byte != b'\n' {
+                        if line.len() < MAX_LINE_LENGTH {
+                            line.push(*byte);
+                        }
+                        continue;
+                    }
+                    if String::from_utf8_lossy(&line).trim() == READY_MARKER {
+                        let ready_at = &mut vm_vec.lock().unwrap()[vm_id as usize].ready_at;
+                        if ready_at.is_none() {
+                            info!("The guest reported ready");
+                            *ready_at = Some(unix_now());
+                        }
+                    }
+                    line.clear();
+                }
+                if let Err(e) = log.write(&buffer[..count]) {
+                    error!("Cannot write the console log: {}", e);
+                }
+                // Fails only when nobody is attached
+                let _ = output.send(buffer[..count].to_vec());
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // The pty reports EIO once the hypervisor closes it
+            Err(_) => break,
+        }
+    }
+    let mut consoles = CONSOLES.lock().unwrap();
+    if consoles.get(&vm_id).is_some_and(|hub| hub.output.same_channel(&output)) {
+        consoles.remove(&vm_id);
+    }
+    drop(consoles);
+    info!("The serial console was closed");
+    Ok(())
+}
+
+// Copy the serial output of a booting VM into its rotating console log and watch for the ready marker
+pub fn spawn_console_capture(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
+    let span = info_span!("console", vm_id);
+    let vm_vec = Arc::clone(vm_vec);
+    thread::spawn(move || {
+        let _entered = span.enter();
+        if let Err(e) = capture_console(&vm_vec, vm_id) {
+            error!("{}", e);
+        }
+    });
+}
+
+// Last lines of the console, reading back through the rotated files when needed
+pub fn read_console_log(vm_id: i16, tail: usize) -> Result<String, String> {
+    let path = console_log_path(vm_id);
+    let files: Vec<String> = std::iter::once(path.clone())
+        .chain((1..=SERIAL_LOG_KEEP).map(|index| rotated_path(&path, index)))
+        .collect();
+    if !files.iter().any(|file| fs::metadata(file).is_ok()) {
+        return Err(format!("No console log for vm_id: {}", vm_id));
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    for file in files.iter() {
+        if lines.len() >= tail {
+            break;
+        }
+        let content = match fs::read(file) {
+            Ok(content) => String::from_utf8_lossy(&content).to_string(),
+            Err(_) => continue,
+        };
+        let mut older: Vec<String> = content.lines().map(|line| line.to_string()).collect();
+        older.append(&mut lines);
+        lines = older;
+    }
+
+    let skip = lines.len().saturating_sub(tail);
+    let mut output = lines[skip..].join("\n");
+    output.push('\n');
+    Ok(output)
+}
@@ -0,0 +1,127 @@
+use std::{
+    net::{IpAddr, SocketAddr, TcpStream},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+    fs,
+};
+use serde_json::Value;
+
+use crate::main_lib::structure::{MAXVM, VmHealth, GuestProbe};
+use crate::main_lib::init_vm::{load_vm_spec, save_vm_spec, lock_vm_spec};
+use crate::main_lib::manage_vm::{call_vmm_api_with_timeout, get_vm_proc_id};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const VMM_API_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// The pid must still be a live cloud-hypervisor serving this VM, not a zombie or a reused pid
+pub fn is_vmm_process_alive(vm_id: i16, pid: &str) -> bool {
+    if pid.is_empty() || pid == "None" {
+        return false;
+    }
+
+    let running = fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| stat.rsplit_once(')').map(|(_, rest)| rest.trim_start().to_string()))
+        .map(|rest| !rest.starts_with('Z') && !rest.starts_with('X'))
+        .unwrap_or(false);
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    running && String::from_utf8_lossy(&cmdline).contains(&format!("/tmp/cloud-hypervisor{}.sock", vm_id))
+}
+
+// vmm.ping tells whether the VMM answers, vm.info gives the VM state
+pub fn get_vmm_state(vm_id: i16) -> String {
+    if call_vmm_api_with_timeout(vm_id, "GET", "vmm.ping", None, VMM_API_TIMEOUT).is_err() {
+        return "unresponsive".to_string();
+    }
+
+    call_vmm_api_with_timeout(vm_id, "GET", "vm.info", None, VMM_API_TIMEOUT).ok()
+        .and_then(|info| serde_json::from_str::<Value>(&info).ok())
+        .and_then(|info| info["state"].as_str().map(|state| state.to_ascii_lowercase()))
+        .unwrap_or("unresponsive".to_string())
+}
+
+pub fn validate_probes(probes: &[GuestProbe]) -> Result<(), String> {
+    for probe in probes {
+        let host = match probe {
+            GuestProbe::Tcp { port: 0, .. } => return Err("the tcp probe port must not be 0".to_string()),
+            GuestProbe::Tcp { host, .. } | GuestProbe::Ping { host } => host,
+        };
+        if let Some(host) = host {
+            if host.parse::<IpAddr>().is_err() {
+                return Err(format!("'{}' is not a valid IP address", host));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn set_vm_probes(vm_id: i16, probes: Vec<GuestProbe>) -> Result<(), String> {
+    if vm_id < 0 || vm_id as usize >= MAXVM {
+        return Err(format!("vm id {} is out of range", vm_id));
+    }
+    validate_probes(&probes)?;
+    let _guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut spec = load_vm_spec(&config_path)
+        .map_err(|e| format!("Cannot load the VM spec: {}", e))?;
+    spec.probes = probes;
+    save_vm_spec(&config_path, &spec).map_err(|e| format!("Cannot save the VM spec: {}", e))
+}
+
+pub fn run_guest_probe(vm_id: i16, probe: &GuestProbe) -> bool {
+    let default_host = format!("192.168.{}.2", vm_id);
+    match probe {
+        GuestProbe::Tcp { port, host } => {
+            let host = host.as_deref().unwrap_or(&default_host);
+            match format!("{}:{}", host, port).parse::<SocketAddr>() {
+                Ok(addr) => TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).is_ok(),
+                Err(_) => false,
+            }
+        }
+        GuestProbe::Ping { host } => {
+            let host = host.as_deref().unwrap_or(&default_host);
+            match host.parse() {
+                Ok(addr) => {
+                    let data = [1,2,3];
+                    let options = ping_rs::PingOptions { ttl: 128, dont_fragment: true };
+                    ping_rs::send_ping(&addr, PROBE_TIMEOUT, &data, Some(&options)).is_ok()
+                }
+                Err(_) => false,
+            }
+        }
+    }
+}
+
+// Combine the process, the VMM and the guest probes, returning the pid that was checked
+pub fn check_vm_health(vm_id: i16, pid: &str) -> (String, VmHealth) {
+    let mut pid = pid.to_string();
+    if !is_vmm_process_alive(vm_id, &pid) {
+        pid = get_vm_proc_id(vm_id);
+    }
+
+    let vmm_state = if is_vmm_process_alive(vm_id, &pid) {
+        get_vmm_state(vm_id)
+    } else {
+        pid = String::new();
+        "not_running".to_string()
+    };
+
+    // Guest probes only make sense when the VM runs
+    let probes = load_vm_spec(&format!("../vms-config/{}", vm_id))
+        .map(|spec| spec.probes)
+        .unwrap_or_default();
+    let guest_reachable = if probes.is_empty() {
+        None
+    } else if vmm_state == "running" {
+        Some(probes.iter().all(|probe| run_guest_probe(vm_id, probe)))
+    } else {
+        Some(false)
+    };
+
+    (pid, VmHealth { vmm_state, guest_reachable, checked_at: unix_now(), probe_latency_ms: 0 })
+}
@@ -0,0 +1,205 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    collections::BTreeSet,
+    path::Path,
+    process::Command,
+    fs,
+};
+use tracing::{warn};
+
+use crate::main_lib::structure::{HostResource, HostCpu, HostMemory, NumaNode, CommittedResources, 
+                                 AdmissionPolicy, PciLedger};
+use crate::main_lib::vfio::sysfs_root;
+use crate::main_lib::init_vm::list_vm_specs;
+use crate::main_lib::manage_storage::{list_pools, disks_total, parse_size};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+const DEFAULT_CPU_OVERCOMMIT: f64 = 4.0;
+const DEFAULT_RAM_OVERCOMMIT: f64 = 1.0;
+const DEFAULT_RESERVED_CPUS: u64 = 0;
+const DEFAULT_RESERVED_RAM: u64 = GIB / 2;
+
+// Serializes the capacity check and the spec write of concurrent VM creations
+pub static ADMISSION_LOCK: Mutex<()> = Mutex::new(());
+
+fn list_indexed(path: &str, prefix: &str) -> Vec<u32> {
+    let mut indexes: Vec<u32> = fs::read_dir(path)
+        .map(|entries| entries.flatten()
+            .filter_map(|entry| entry.file_name().to_string_lossy().strip_prefix(prefix)?.parse().ok())
+            .collect())
+        .unwrap_or_default();
+    indexes.sort();
+    indexes
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|value| value.trim().to_string())
+}
+
+// Value in bytes of a "Key: 1234 kB" line, pages counts have no unit
+fn meminfo_value(content: &str, key: &str) -> u64 {
+    content.lines()
+        .find_map(|line| {
+            let rest = line.split_once(&format!("{}:", key))?.1;
+            let mut fields = rest.split_whitespace();
+            let value: u64 = fields.next()?.parse().ok()?;
+            match fields.next() {
+                Some("kB") => Some(value * 1024),
+                _ => Some(value),
+            }
+        })
+        .unwrap_or(0)
+}
+
+pub fn get_host_cpu() -> HostCpu {
+    let cpu_path = format!("{}/devices/system/cpu", sysfs_root());
+    let cpus = list_indexed(&cpu_path, "cpu");
+
+    let mut sockets = BTreeSet::new();
+    let mut cores = BTreeSet::new();
+    for cpu in cpus.iter() {
+        let topology = format!("{}/cpu{}/topology", cpu_path, cpu);
+        let package = read_trimmed(&format!("{}/physical_package_id", topology)).unwrap_or_default();
+        let core = read_trimmed(&format!("{}/core_id", topology)).unwrap_or_default();
+        sockets.insert(package.clone());
+        cores.insert((package, core));
+    }
+
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    let model = cpuinfo.lines()
+        .find(|line| line.starts_with("model name"))
+        .and_then(|line| line.split_once(':'))
+        .map(|(_, model)| model.trim().to_string());
+    let flags = cpuinfo.lines().find(|line| line.starts_with("flags")).unwrap_or("");
+    let virtualization = if flags.split_whitespace().any(|flag| flag == "vmx") {
+        Some("vmx".to_string())
+    } else if flags.split_whitespace().any(|flag| flag == "svm") {
+        Some("svm".to_string())
+    } else {
+        None
+    };
+
+    HostCpu {
+        model,
+        sockets: sockets.len(),
+        cores: cores.len(),
+        threads: cpus.len(),
+        virtualization,
+    }
+}
+
+pub fn get_host_memory() -> HostMemory {
+    let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    HostMemory {
+        total: meminfo_value(&meminfo, "MemTotal"),
+        free: meminfo_value(&meminfo, "MemFree"),
+        available: meminfo_value(&meminfo, "MemAvailable"),
+        hugepages_total: meminfo_value(&meminfo, "HugePages_Total"),
+        hugepages_free: meminfo_value(&meminfo, "HugePages_Free"),
+        hugepage_size: meminfo_value(&meminfo, "Hugepagesize"),
+    }
+}
+
+pub fn get_numa_nodes() -> Vec<NumaNode> {
+    let node_path = format!("{}/devices/system/node", sysfs_root());
+    list_indexed(&node_path, "node").into_iter()
+        .map(|id| {
+            let path = format!("{}/node{}", node_path, id);
+            let meminfo = fs::read_to_string(format!("{}/meminfo", path)).unwrap_or_default();
+            NumaNode {
+                id,
+                cpus: read_trimmed(&format!("{}/cpulist", path)).unwrap_or_default(),
+                memory_total: meminfo_value(&meminfo, "MemTotal"),
+                memory_free: meminfo_value(&meminfo, "MemFree"),
+            }
+        })
+        .collect()
+}
+
+fn get_hypervisor_version() -> Option<String> {
+    let output = Command::new("cloud-hypervisor").arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub fn get_committed_resources(pci_ledger: &Arc<Mutex<PciLedger>>) -> CommittedResources {
+    let specs = list_vm_specs();
+    CommittedResources {
+        vms: specs.len(),
+        cpus: specs.iter().map(|spec| spec.cpu.max(0) as u64).sum(),
+        ram: specs.iter().map(|spec| spec.ram.max(0) as u64 * GIB).sum(),
+        storage: specs.iter().map(disks_total).sum(),
+        pci_devices: pci_ledger.lock().unwrap().len(),
+    }
+}
+
+pub fn get_host_resource(node: &str, pci_ledger: &Arc<Mutex<PciLedger>>) -> HostResource {
+    let iommu_groups = format!("{}/kernel/iommu_groups", sysfs_root());
+    HostResource {
+        node: node.to_string(),
+        cpu: get_host_cpu(),
+        memory: get_host_memory(),
+        numa_nodes: get_numa_nodes(),
+        pools: list_pools(),
+        kvm: Path::new("/dev/kvm").exists(),
+        iommu: fs::read_dir(iommu_groups).map(|mut groups| groups.next().is_some()).unwrap_or(false),
+        hypervisor_version: get_hypervisor_version(),
+        committed: get_committed_resources(pci_ledger),
+        policy: get_admission_policy(),
+    }
+}
+
+fn env_ratio(name: &str, default: f64) -> f64 {
+    match env::var(name).ok().map(|value| value.parse::<f64>()) {
+        Some(Ok(ratio)) if ratio > 0.0 => ratio,
+        Some(_) => {
+            warn!("Invalid {}, using {}", name, default);
+            default
+        }
+        None => default,
+    }
+}
+
+// The policy is read from CHV_CPU_OVERCOMMIT, CHV_RAM_OVERCOMMIT, CHV_RESERVED_CPUS 
+// and CHV_RESERVED_RAM (a size such as 2G)
+pub fn get_admission_policy() -> AdmissionPolicy {
+    AdmissionPolicy {
+        cpu_overcommit: env_ratio("CHV_CPU_OVERCOMMIT", DEFAULT_CPU_OVERCOMMIT),
+        ram_overcommit: env_ratio("CHV_RAM_OVERCOMMIT", DEFAULT_RAM_OVERCOMMIT),
+        reserved_cpus: env::var("CHV_RESERVED_CPUS").ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_RESERVED_CPUS),
+        reserved_ram: env::var("CHV_RESERVED_RAM").ok()
+            .and_then(|value| parse_size(&value).ok())
+            .unwrap_or(DEFAULT_RESERVED_RAM),
+    }
+}
+
+// Every defined VM counts against the node, stopped ones can be started at any time
+// Storage is checked by create against the image and pool sizes
+pub fn check_admission(cpu: i32, ram: i32, pci_ledger: &Arc<Mutex<PciLedger>>) -> Result<(), String> {
+    let policy = get_admission_policy();
+    let committed = get_committed_resources(pci_ledger);
+    let threads = get_host_cpu().threads as u64;
+    let memory = get_host_memory().total;
+
+    let cpu_capacity = (threads.saturating_sub(policy.reserved_cpus) as f64 * policy.cpu_overcommit) as u64;
+    let cpu_needed = committed.cpus + cpu.max(0) as u64;
+    if cpu_needed > cpu_capacity {
+        return Err(format!(
+            "Not enough CPU: {} vCPUs requested, {} committed of {} allowed ({} threads, {} reserved, overcommit {})",
+            cpu, committed.cpus, cpu_capacity, threads, policy.reserved_cpus, policy.cpu_overcommit));
+    }
+
+    let ram_capacity = (memory.saturating_sub(policy.reserved_ram) as f64 * policy.ram_overcommit) as u64;
+    let ram_needed = committed.ram + ram.max(0) as u64 * GIB;
+    if ram_needed > ram_capacity {
+        return Err(format!(
+            "Not enough memory: {} bytes requested, {} committed of {} allowed ({} total, {} reserved, overcommit {})",
+            ram.max(0) as u64 * GIB, committed.ram, ram_capacity, memory, policy.reserved_ram, policy.ram_overcommit));
+    }
+    Ok(())
+}
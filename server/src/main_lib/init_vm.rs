@@ -4,9 +4,19 @@ use std::{
     io::Write,
     fs,
     fs::OpenOptions,
+    sync::{Mutex, MutexGuard},
 };
+use tracing::{error, info};
 
-pub fn get_cloud_image(config_path: &str, url: &str) {
+use crate::main_lib::structure::{MAXVM, VmSpec};
+use crate::main_lib::manage_storage::{disk_cli_arg};
+use crate::main_lib::manage_pci::{device_cli_arg};
+use crate::main_lib::mdev::{mdev_cli_arg};
+use crate::main_lib::logging::{log_output};
+use crate::main_lib::cloud_init::{build_user_data, build_vendor_data, build_meta_data, instance_id};
+
+// Download the image into the ../os cache unless it is already there
+pub fn fetch_cloud_image(url: &str) {
     let filename = url.rsplit('/').next().unwrap_or("");
     let os_file_path = &format!("../os/{}", filename);
     let file_exist = Path::new(os_file_path).exists();
@@ -16,30 +26,68 @@ pub fn get_cloud_image(config_path: &str, url: &str) {
         .output() {
             Ok(output) => {
                 if !output.status.success() {
-                    eprintln!("Command failed: {:?}", String::from_utf8_lossy(&output.stderr));
+                    log_output("wget", &output);
+                    error!(code = output.status.code(), "Command failed");
                 }
             }
-            Err(e) => eprintln!("Failed to execute command: {}", e),
+            Err(e) => error!("Failed to execute command: {}", e),
         }
     }
     else {
-        println!("[Skipped] Cloud image found locally");
+        info!("[Skipped] Cloud image found locally");
     }
+}
+
+pub fn get_cloud_image(disk_dir: &str, url: &str) {
+    let filename = url.rsplit('/').next().unwrap_or("");
+    let os_file_path = &format!("../os/{}", filename);
+    fetch_cloud_image(url);
 
-    let image_name = filename.split(".").nth(0).unwrap_or("");
+    let image_name = filename.split(".").next().unwrap_or("");
     match Command::new("sh").arg("-c")
         .arg(format!("qemu-img convert -p -f qcow2 -O raw {} {}/{}.raw", 
-                        os_file_path, config_path, image_name))
+                        os_file_path, disk_dir, image_name))
         .output() {
             Ok(output) => {
                 if !output.status.success() {
-                    eprintln!("Command failed: {:?}", String::from_utf8_lossy(&output.stderr));
+                    log_output("qemu-img", &output);
+                    error!(code = output.status.code(), "Command failed");
                 }
             }
-            Err(e) => eprintln!("Failed to execute command: {}", e),
+            Err(e) => error!("Failed to execute command: {}", e),
         }
 }
 
+// Bytes the image takes once converted into the pool and whether that is exact: the virtual
+// size when it is cached, otherwise the download size of the compressed qcow2 as a lower bound
+pub fn get_image_size(url: &str) -> (u64, bool) {
+    let filename = url.rsplit('/').next().unwrap_or("");
+    let os_file_path = format!("../os/{}", filename);
+    if Path::new(&os_file_path).exists() {
+        let virtual_size = Command::new("qemu-img").arg("info").arg("--output=json").arg(&os_file_path)
+            .output().ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| serde_json::from_slice::<serde_json::Value>(&output.stdout).ok())
+            .and_then(|info| info["virtual-size"].as_u64());
+        return match virtual_size {
+            Some(size) => (size, true),
+            None => (fs::metadata(&os_file_path).map(|metadata| metadata.len()).unwrap_or(0), false),
+        };
+    }
+
+    let download_size = Command::new("curl").arg("--silent").arg("--head").arg("--location")
+        .arg("--max-time").arg("10").arg(url)
+        .output().ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        // The last response is the image itself once the redirects were followed
+        .and_then(|headers| headers.lines().rev()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .find_map(|(_, value)| value.trim().parse::<u64>().ok()))
+        .unwrap_or(0);
+    (download_size, false)
+}
+
 pub fn write_cloud_config(vm_id: i16, config_path: &str) -> std::io::Result<()> {
     let file_path = format!("{}/cloud-config.sh", config_path);
     let content = format!(r#"#!/usr/bin/env bash
@@ -49,8 +97,10 @@ rm -f ../storage/cloudinit{}.img
 mkdosfs -n CIDATA -C ../storage/cloudinit{}.img 8156
 mcopy -oi ../storage/cloudinit{}.img -s {}/user-data ::
 mcopy -oi ../storage/cloudinit{}.img -s {}/meta-data ::
-mcopy -oi ../storage/cloudinit{}.img -s {}/network-config ::"#,
-    vm_id, vm_id, vm_id, config_path, vm_id, config_path, vm_id, config_path);
+mcopy -oi ../storage/cloudinit{}.img -s {}/network-config ::
+mcopy -oi ../storage/cloudinit{}.img -s {}/vendor-data ::"#,
+    vm_id, vm_id, vm_id, config_path, vm_id, config_path, vm_id, config_path,
+    vm_id, config_path);
 
     let mut file = OpenOptions::new()
         .write(true)
@@ -60,31 +110,20 @@ mcopy -oi ../storage/cloudinit{}.img -s {}/network-config ::"#,
 
     file.write_all(content.as_bytes())?;
 
-    println!("File written successfully!");
+    info!("File written successfully!");
     Ok(())
 }
 
-pub fn create_cloud_init_files(config_path: &str, username: &str, password: &str, 
+pub fn create_cloud_init_files(config_path: &str, spec: &VmSpec, 
                             ip: &str, ip_gw: &str) -> std::io::Result<()> {
-    // Create user-data content
-    let user_data = format!(
-        "#cloud-config
-users:
-  - name: {}
-    passwd: {}
-    sudo: ALL=(ALL) NOPASSWD:ALL
-    lock_passwd: False
-    inactive: False
-    shell: /bin/bash
-
-ssh_pwauth: True
-",
-        username, password
-    );
+    // Create user-data and vendor-data content
+    let user_data = build_user_data(&spec.username, &spec.password, spec.user_data.as_deref())
+        .map_err(std::io::Error::other)?;
+    let vendor_data = build_vendor_data(spec.vendor_data.as_deref())
+        .map_err(std::io::Error::other)?;
 
     // Create meta-data content
-    let meta_data = "instance-id: cloud\n\
-                     local-hostname: cloud\n";
+    let meta_data = build_meta_data(spec).map_err(std::io::Error::other)?;
 
     // Create network-config content
     let network_config = format!(
@@ -109,22 +148,91 @@ ethernets:
     let mut file = fs::File::create(format!("{}/network-config", config_path))?;
     file.write_all(network_config.as_bytes())?;
 
+    let mut file = fs::File::create(format!("{}/vendor-data", config_path))?;
+    file.write_all(vendor_data.as_bytes())?;
+
     Ok(())
 }
 
-pub fn write_vm_config(vm_id: i16, config_path: &str, url: &str, cpu: i32, ram: i32) 
-                        -> std::io::Result<()> {
-    let image = url.rsplit('/').next().unwrap_or("").split('.').nth(0).unwrap_or("");
+// Serializes the load, modify and save cycles on the spec of each VM
+static SPEC_LOCKS: [Mutex<()>; MAXVM] = [const { Mutex::new(()) }; MAXVM];
+
+// Hold the guard from load_vm_spec until save_vm_spec so concurrent updates are not lost
+pub fn lock_vm_spec(vm_id: i16) -> MutexGuard<'static, ()> {
+    SPEC_LOCKS[vm_id as usize].lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn save_vm_spec(config_path: &str, spec: &VmSpec) -> std::io::Result<()> {
+    let content = serde_json::to_string_pretty(spec)?;
+    let mut file = fs::File::create(format!("{}/vm-spec.json", config_path))?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+pub fn load_vm_spec(config_path: &str) -> std::io::Result<VmSpec> {
+    let content = fs::read_to_string(format!("{}/vm-spec.json", config_path))?;
+    let spec: VmSpec = serde_json::from_str(&content)?;
+    Ok(spec)
+}
+
+pub fn list_vm_specs() -> Vec<VmSpec> {
+    let entries = match fs::read_dir("../vms-config") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries.flatten()
+        .filter_map(|entry| load_vm_spec(&entry.path().to_string_lossy()).ok())
+        .collect()
+}
+
+// Bump the instance-id and rebuild the seed image so cloud-init re-runs on the next boot
+pub fn rebuild_seed(vm_id: i16) -> Result<String, String> {
+    if vm_id < 0 || vm_id as usize >= MAXVM {
+        return Err(format!("vm id {} is out of range", vm_id));
+    }
+    let _guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut spec = load_vm_spec(&config_path)
+        .map_err(|e| format!("Cannot load the VM spec: {}", e))?;
+    spec.seed_generation += 1;
+
+    let ip_gw = format!("192.168.{}.1", vm_id);
+    let ip = format!("192.168.{}.2", vm_id);
+    create_cloud_init_files(&config_path, &spec, &ip, &ip_gw)
+        .map_err(|e| format!("Cannot write the cloud-init files: {}", e))?;
+    save_vm_spec(&config_path, &spec)
+        .map_err(|e| format!("Cannot save the VM spec: {}", e))?;
+
+    if run_cloud_init(&config_path) != 1 {
+        return Err("Cannot rebuild the cloud-init seed image".to_string());
+    }
+    Ok(instance_id(&spec))
+}
+
+pub fn write_vm_config(config_path: &str, spec: &VmSpec) -> std::io::Result<()> {
+    let vm_id = spec.vm_id;
     let file_path = format!("{}/vm-config.sh", config_path);
     let ip = format!("ip=192.168.{}.1,mask=255.255.255.0", vm_id);
+    let disks: Vec<String> = spec.disks.iter().map(disk_cli_arg).collect();
+    let devices: Vec<String> = spec.devices.iter().map(device_cli_arg)
+        .chain(spec.mdevs.iter().map(mdev_cli_arg))
+        .collect();
+    let device_args = if devices.is_empty() {
+        String::new()
+    } else {
+        format!(" \\\n    --device {}", devices.join(" "))
+    };
     let content = format!(r#"cloud-hypervisor \
     --api-socket /tmp/cloud-hypervisor{}.sock \
     --kernel ../os/hypervisor-fw \
-    --disk path={}/{}.raw path=../storage/cloudinit{}.img \
+    --disk {} path=../storage/cloudinit{}.img,id=seed \
     --cpus boot={} \
     --memory size={}G \
-    --net "tap=vmtap{},mac=ae:00:22:d0:d9:6f,{}""#, 
-    vm_id, config_path, image, vm_id, cpu, ram, vm_id, ip);
+    --serial pty \
+    --console off \
+    --net "tap=vmtap{},mac=ae:00:22:d0:d9:6f,{}"{}"#, 
+    vm_id, disks.join(" "), vm_id, spec.cpu, spec.ram, vm_id, ip, device_args);
 
     let mut file = OpenOptions::new()
         .write(true)
@@ -135,7 +243,7 @@ pub fn write_vm_config(vm_id: i16, config_path: &str, url: &str, cpu: i32, ram:
     // Write content to the file
     file.write_all(content.as_bytes())?;
 
-    println!("File written successfully!");
+    info!("File written successfully!");
     Ok(())
 }
 
@@ -145,14 +253,15 @@ pub fn run_cloud_init(config_path: &str) -> i32 {
         .output() {
             Ok(output) => {
                 if !output.status.success() {
-                    eprintln!("Command failed: {:?}", String::from_utf8_lossy(&output.stderr));
+                    log_output("cloud-config.sh", &output);
+                    error!(code = output.status.code(), "Command failed");
                     return -1;
                 }
             }
             Err(e) => {
-                eprintln!("Failed to execute command: {}", e);
+                error!("Failed to execute command: {}", e);
                 return -1;
             }
         }
-    return 1;
+    1
 }
\ No newline at end of file
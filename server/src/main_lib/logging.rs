@@ -0,0 +1,72 @@
+use std::{
+    env,
+    io::{BufRead, BufReader, Read},
+    process::{Command, ExitStatus, Output, Stdio},
+    thread,
+};
+
+use tracing::{info, warn, Span};
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+// The level is read from CHV_LOG (an env filter such as "debug" or "server=debug,hyper=warn"),
+// CHV_LOG_FORMAT=json switches to one JSON object per line
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_env("CHV_LOG")
+        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false);
+
+    match env::var("CHV_LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().with_current_span(true).with_span_list(true).init(),
+        _ => builder.init(),
+    }
+}
+
+// Attach the stdout and stderr of a finished command to the current span
+pub fn log_output(program: &str, output: &Output) {
+    for line in String::from_utf8_lossy(&output.stdout).lines().filter(|line| !line.trim().is_empty()) {
+        info!(program, stream = "stdout", "{}", line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines().filter(|line| !line.trim().is_empty()) {
+        warn!(program, stream = "stderr", "{}", line);
+    }
+}
+
+fn forward_lines<R: Read + Send + 'static>(program: String, stream: &'static str, 
+                                           reader: R) -> thread::JoinHandle<()> {
+    let span = Span::current();
+    thread::spawn(move || {
+        let _entered = span.enter();
+        for line in BufReader::new(reader).lines().map_while(Result::ok) {
+            if stream == "stderr" {
+                warn!(program = %program, stream, "{}", line);
+            } else {
+                info!(program = %program, stream, "{}", line);
+            }
+        }
+    })
+}
+
+// Run a long lived process such as the hypervisor and stream its output 
+// into the current span line by line until it exits
+pub fn run_logged(program: &str, command: &mut Command) -> std::io::Result<ExitStatus> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    info!(program, pid = child.id(), "Process started");
+
+    let readers = [
+        child.stdout.take().map(|stdout| forward_lines(program.to_string(), "stdout", stdout)),
+        child.stderr.take().map(|stderr| forward_lines(program.to_string(), "stderr", stderr)),
+    ];
+    let status = child.wait()?;
+    for reader in readers.into_iter().flatten() {
+        let _ = reader.join();
+    }
+    info!(program, code = status.code(), "Process exited");
+    Ok(status)
+}
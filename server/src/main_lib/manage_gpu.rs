@@ -0,0 +1,243 @@
+use std::{
+    sync::{Arc, Mutex},
+    collections::BTreeMap,
+    fs,
+};
+use pci_ids::{Device, FromId, Vendor};
+
+use crate::main_lib::structure::{GpuInfo, HostGpu, PciLedger, allocate_pci, release_pci};
+use crate::main_lib::vfio::{sysfs_root, pci_device_path, get_pci_driver, get_iommu_group, 
+                            get_numa_node};
+use crate::main_lib::manage_pci::{resolve_iommu_groups};
+
+// IORESOURCE_MEM and IORESOURCE_PREFETCH flags of the sysfs resource file
+const RESOURCE_MEM: u64 = 0x200;
+const RESOURCE_PREFETCH: u64 = 0x2000;
+
+fn read_hex(path: &str) -> Option<u64> {
+    let content = fs::read_to_string(path).ok()?;
+    u64::from_str_radix(content.trim().trim_start_matches("0x"), 16).ok()
+}
+
+// The largest prefetchable memory BAR is the VRAM aperture on discrete GPUs
+fn get_vram(bdf: &str) -> Option<u64> {
+    let content = fs::read_to_string(format!("{}/resource", pci_device_path(bdf))).ok()?;
+    content.lines()
+        .filter_map(|line| {
+            let fields: Vec<u64> = line.split_whitespace()
+                .filter_map(|field| u64::from_str_radix(field.trim_start_matches("0x"), 16).ok())
+                .collect();
+            match fields.as_slice() {
+                [start, end, flags] if *end > *start 
+                    && flags & RESOURCE_MEM != 0 && flags & RESOURCE_PREFETCH != 0 => {
+                    Some(end - start + 1)
+                }
+                _ => None,
+            }
+        })
+        .max()
+}
+
+fn list_pci_addresses() -> Vec<String> {
+    let mut addresses: Vec<String> = fs::read_dir(format!("{}/bus/pci/devices", sysfs_root()))
+        .map(|entries| entries.flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect())
+        .unwrap_or_default();
+    addresses.sort();
+    addresses
+}
+
+// Other functions of the same slot, such as the HDMI audio controller
+fn get_companions(bdf: &str, addresses: &[String]) -> Vec<String> {
+    let slot = bdf.rsplit_once('.').map(|(slot, _)| slot).unwrap_or(bdf);
+    addresses.iter()
+        .filter(|address| address.as_str() != bdf && address.starts_with(&format!("{}.", slot)))
+        .cloned()
+        .collect()
+}
+
+pub fn get_gpu_inventory(pci_ledger: &Arc<Mutex<PciLedger>>) -> Vec<GpuInfo> {
+    let ledger = pci_ledger.lock().unwrap().clone();
+    let addresses = list_pci_addresses();
+
+    let mut gpus = Vec::new();
+    for bdf in addresses.iter() {
+        let class = read_hex(&format!("{}/class", pci_device_path(bdf))).unwrap_or(0);
+        if class >> 16 != 0x03 {
+            continue;
+        }
+
+        let vendor_id = read_hex(&format!("{}/vendor", pci_device_path(bdf))).unwrap_or(0) as u16;
+        let device_id = read_hex(&format!("{}/device", pci_device_path(bdf))).unwrap_or(0) as u16;
+        let vendor_name = match Vendor::from_id(vendor_id) {
+            Some(v) => v.name().to_string(),
+            None => format!("vendor ({:04x})", vendor_id),
+        };
+        let model = match Device::from_vid_pid(vendor_id, device_id) {
+            Some(d) => d.name().to_string(),
+            None => format!("Unknown device ({:04x})", device_id),
+        };
+
+        gpus.push(GpuInfo {
+            address: bdf.clone(),
+            vendor_id,
+            device_id,
+            vendor_name,
+            model,
+            vram: get_vram(bdf),
+            numa_node: get_numa_node(bdf),
+            iommu_group: get_iommu_group(bdf),
+            companions: get_companions(bdf, &addresses),
+            driver: get_pci_driver(bdf),
+            assigned_to: ledger.get(bdf).cloned(),
+        });
+    }
+    gpus
+}
+
+// Match on the model name or the vendor:device id, and on the vendor name or id when given
+fn gpu_matches(gpu: &GpuInfo, request: &HostGpu) -> bool {
+    let model = request.model.to_ascii_lowercase();
+    let ids = format!("{:04x}:{:04x}", gpu.vendor_id, gpu.device_id);
+    let name = format!("{} {}", gpu.vendor_name, gpu.model).to_ascii_lowercase();
+    let vendor_matches = match request.vendor.as_deref() {
+        Some(vendor) => {
+            let vendor = vendor.to_ascii_lowercase();
+            vendor == format!("{:04x}", gpu.vendor_id) 
+                || gpu.vendor_name.to_ascii_lowercase().contains(&vendor)
+        }
+        None => true,
+    };
+    vendor_matches && (ids == model || name.contains(&model))
+}
+
+// Pick free GPUs for every request, all of them or none
+pub fn schedule_gpus(requests: &[HostGpu], inventory: &[GpuInfo]) -> Result<Vec<String>, String> {
+    let mut selected: Vec<String> = Vec::new();
+    for request in requests {
+        if request.count <= 0 {
+            return Err(format!("The count of '{}' must be greater than zero", request.model));
+        }
+        let count = request.count as usize;
+        let candidates: Vec<&GpuInfo> = inventory.iter()
+            .filter(|gpu| gpu.assigned_to.is_none() && gpu.iommu_group.is_some())
+            .filter(|gpu| !selected.contains(&gpu.address))
+            .filter(|gpu| gpu_matches(gpu, request))
+            .collect();
+
+        let picked: Vec<&GpuInfo> = if request.same_numa {
+            let mut by_node: BTreeMap<i32, Vec<&GpuInfo>> = BTreeMap::new();
+            for gpu in candidates.iter() {
+                by_node.entry(gpu.numa_node).or_default().push(gpu);
+            }
+            by_node.into_values()
+                .find(|gpus| gpus.len() >= count)
+                .map(|gpus| gpus.into_iter().take(count).collect())
+                .ok_or(format!("No NUMA node has {} free '{}' gpus", count, request.model))?
+        } else {
+            if candidates.len() < count {
+                return Err(format!("Only {} free '{}' gpus, {} requested", 
+                                    candidates.len(), request.model, count));
+            }
+            candidates.into_iter().take(count).collect()
+        };
+
+        selected.extend(picked.iter().map(|gpu| gpu.address.clone()));
+    }
+    Ok(selected)
+}
+
+// Reserve the selected GPUs and their IOMMU group members before attaching them
+pub fn reserve_gpus(vm_id: i16, selected: &[String], pci_ledger: &Arc<Mutex<PciLedger>>) 
+                    -> Result<Vec<String>, String> {
+    let devices = resolve_iommu_groups(selected, true)?;
+    let mut reserved: Vec<String> = Vec::new();
+    for bdf in devices.iter() {
+        // Devices the VM already held are not ours to release
+        match allocate_pci(bdf, vm_id, pci_ledger) {
+            Ok(true) => reserved.push(bdf.clone()),
+            Ok(false) => {}
+            Err(e) => {
+                for bdf in reserved.iter() {
+                    release_pci(bdf, vm_id, pci_ledger);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_lib::structure::PciAllocation;
+
+    fn gpu(address: &str, device_id: u16, model: &str, numa_node: i32) -> GpuInfo {
+        GpuInfo {
+            address: address.to_string(),
+            vendor_id: 0x10de,
+            device_id,
+            vendor_name: "NVIDIA Corporation".to_string(),
+            model: model.to_string(),
+            vram: None,
+            numa_node,
+            iommu_group: Some(address.to_string()),
+            companions: Vec::new(),
+            driver: None,
+            assigned_to: None,
+        }
+    }
+
+    fn request(model: &str, count: i32, same_numa: bool) -> HostGpu {
+        HostGpu { model: model.to_string(), count, vendor: None, same_numa }
+    }
+
+    fn inventory() -> Vec<GpuInfo> {
+        vec![
+            gpu("0000:17:00.0", 0x20b5, "A100 PCIe 80GB", 0),
+            gpu("0000:31:00.0", 0x20b5, "A100 PCIe 80GB", 1),
+            gpu("0000:4b:00.0", 0x20b5, "A100 PCIe 80GB", 1),
+            gpu("0000:ca:00.0", 0x2236, "A10", 1),
+        ]
+    }
+
+    #[test]
+    fn schedule_by_model_and_ids() {
+        let inventory = inventory();
+
+        assert_eq!(schedule_gpus(&[request("a100", 2, false)], &inventory),
+                   Ok(vec!["0000:17:00.0".to_string(), "0000:31:00.0".to_string()]));
+        assert_eq!(schedule_gpus(&[request("10de:2236", 1, false)], &inventory),
+                   Ok(vec!["0000:ca:00.0".to_string()]));
+    }
+
+    #[test]
+    fn schedule_same_numa_picks_one_node() {
+        let inventory = inventory();
+
+        assert_eq!(schedule_gpus(&[request("a100", 2, true)], &inventory),
+                   Ok(vec!["0000:31:00.0".to_string(), "0000:4b:00.0".to_string()]));
+        assert!(schedule_gpus(&[request("a100", 3, true)], &inventory).unwrap_err()
+                .contains("No NUMA node"));
+    }
+
+    #[test]
+    fn schedule_skips_assigned_and_selected_gpus() {
+        let mut inventory = inventory();
+        inventory[1].assigned_to = Some(PciAllocation { vm_id: 3, device_id: "_vfio0".to_string() });
+        inventory[2].iommu_group = None;
+
+        assert!(schedule_gpus(&[request("a100", 2, false)], &inventory).unwrap_err()
+                .contains("Only 1 free"));
+        // A request never gets a GPU picked by an earlier request
+        assert!(schedule_gpus(&[request("a100", 1, false), request("a100", 1, false)], &inventory)
+                .is_err());
+    }
+
+    #[test]
+    fn schedule_rejects_empty_counts() {
+        assert!(schedule_gpus(&[request("a100", 0, false)], &inventory()).is_err());
+    }
+}
@@ -1,9 +1,45 @@
 use pci_info::{PciInfo};
 use serde_json::{json, Value};
-use std::process::Command;
+use std::{path::Path, process::Command, sync::{Arc, Mutex}};
 use pci_ids::{Device, FromId, Vendor};
+use tracing::{error, info};
 
-pub async fn get_pcis_info(filter: &str, arg1: &str) -> Vec<Value> {
+use crate::main_lib::structure::{PciFilter, PciLedger, DeviceSpec, HostPci, allocate_pci, allocate_pcis, set_pci_device_id, 
+                                    release_pci, find_vm_pcis};
+use crate::main_lib::init_vm::{load_vm_spec, save_vm_spec, lock_vm_spec};
+use crate::main_lib::manage_vm::{get_vm_config, wait_device_removed, DEVICE_REMOVE_TIMEOUT};
+use crate::main_lib::mdev::{prepare_vm_mdevs};
+use crate::main_lib::vfio::{normalize_bdf, pci_device_path, bind_vfio, restore_driver, get_pci_driver, 
+                            get_iommu_group, get_iommu_group_members, is_pci_bridge, 
+                            get_numa_node, read_pci_attr};
+
+fn parse_hex(value: &str) -> Option<u16> {
+    u16::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+}
+
+// Reject filters that are not hex ids instead of silently matching nothing
+pub fn validate_pci_filter(filter: &PciFilter) -> Result<(), String> {
+    let fields = [("vendor id", &filter.vendor, 0xffff), ("device id", &filter.device, 0xffff),
+                  ("class", &filter.class, 0xff), ("subclass", &filter.subclass, 0xff)];
+    for (name, value, max) in fields {
+        if let Some(value) = value {
+            if parse_hex(value).is_none_or(|id| id > max) {
+                return Err(format!("invalid {} '{}'", name, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn hex_matches(filter: &Option<String>, value: u16) -> bool {
+    match filter {
+        Some(filter) => parse_hex(filter) == Some(value),
+        None => true,
+    }
+}
+
+pub async fn get_pcis_info(filter: &PciFilter, pci_ledger: &Arc<Mutex<PciLedger>>) -> Vec<Value> {
+    let ledger = pci_ledger.lock().unwrap().clone();
     let info = match PciInfo::enumerate_pci() {
         Ok(devices) => devices,
         Err(_) => return vec![json!({"error": "Failed to enumerate PCI devices"})],
@@ -12,17 +48,32 @@ pub async fn get_pcis_info(filter: &str, arg1: &str) -> Vec<Value> {
     let mut devices = Vec::new();
     for r in info {
         if let Ok(device) = r {
-            let location = device
+            let bdf = device
                 .location()
-                .map(|loc| format!("{:02x}:{:02x}.{:x}", loc.bus(), loc.device(), loc.function()))
+                .map(|loc| format!("{:04x}:{:02x}:{:02x}.{:x}", loc.segment(), loc.bus(), 
+                                    loc.device(), loc.function()))
                 .unwrap_or_else(|_| "Unknown".to_string());
-            
             let vendor_id = device.vendor_id();
             let device_id = device.device_id();
             let class_code = device.device_class_code().unwrap_or(0);
             let subclass_code = device.device_subclass_code().unwrap_or(0);
+            let driver = get_pci_driver(&bdf);
+            let iommu_group = get_iommu_group(&bdf);
+            let assigned_to = ledger.get(&bdf);
+
+            if !hex_matches(&filter.vendor, vendor_id) 
+                || !hex_matches(&filter.device, device_id)
+                || !hex_matches(&filter.class, class_code.into())
+                || !hex_matches(&filter.subclass, subclass_code.into()) {
+                continue;
+            }
+            if filter.driver.is_some() && filter.driver != driver { continue };
+            if filter.iommu_group.is_some() && filter.iommu_group != iommu_group { continue };
+            if filter.assigned.is_some_and(|assigned| assigned != assigned_to.is_some()) { continue };
 
-            if filter == "class_code" && arg1 != class_code.to_string() { continue };
+            let iommu_group_members = iommu_group.as_deref()
+                .map(get_iommu_group_members)
+                .unwrap_or_default();
             
             // Look up vendor and device names using pci-ids
             let vendor_name = match Vendor::from_id(vendor_id) {
@@ -36,92 +87,406 @@ pub async fn get_pcis_info(filter: &str, arg1: &str) -> Vec<Value> {
             };
             
             let device_json = json!({
-                "address": location,
+                "address": bdf,
                 "vendor_id": vendor_id,
                 "device_id": device_id,
                 "vendor_name": vendor_name,
                 "device_name": format!("{} {}", vendor_name, device_name),
                 "revision": device.revision().unwrap_or(0),
                 "class_code": class_code,
-                "subclass_code": subclass_code
+                "subclass_code": subclass_code,
+                "driver": driver,
+                "numa_node": get_numa_node(&bdf),
+                "link": {
+                    "speed": read_pci_attr(&bdf, "current_link_speed"),
+                    "width": read_pci_attr(&bdf, "current_link_width"),
+                    "max_speed": read_pci_attr(&bdf, "max_link_speed"),
+                    "max_width": read_pci_attr(&bdf, "max_link_width")
+                },
+                "iommu_group": iommu_group,
+                "iommu_group_members": iommu_group_members,
+                "assigned_to": assigned_to
             });
 
             devices.push(device_json);
         } else {
-            eprintln!("Error reading device information");
+            error!("Error reading device information");
         }
     }
 
     devices
 }
 
-pub fn add_pci_device(vm_id: i16, device_id: &str, retries: i16) -> String {
-    let mut result: String = "None".to_string();
+// Expand the requested devices to whole IOMMU groups, or fail when a group
+// still has host bound devices that were not requested
+pub fn resolve_iommu_groups(addresses: &[String], include_group: bool) -> Result<Vec<String>, String> {
+    let mut resolved: Vec<String> = addresses.iter()
+        .map(|address| normalize_bdf(address))
+        .collect::<Result<_, _>>()?;
+    let mut conflicts = Vec::new();
+
+    for bdf in resolved.clone() {
+        let group = match get_iommu_group(&bdf) {
+            Some(group) => group,
+            None => return Err(format!("The device {} has no IOMMU group, is the IOMMU enabled?", bdf)),
+        };
+
+        for member in get_iommu_group_members(&group) {
+            if resolved.contains(&member) || is_pci_bridge(&member) {
+                continue;
+            }
+            if include_group {
+                info!("Including the device {} from the IOMMU group {}", member, group);
+                resolved.push(member);
+            } else {
+                match get_pci_driver(&member) {
+                    Some(driver) if driver != "vfio-pci" => {
+                        conflicts.push(format!("{} (group {}, bound to {})", member, group, driver));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(format!("The IOMMU groups of the requested devices also contain host bound \
+                            devices: {}. Request them too or set include_group", conflicts.join(", ")));
+    }
+    Ok(resolved)
+}
+
+// Remember the host driver of a passed through device so it can be restored
+fn record_host_driver(vm_id: i16, bdf: &str, driver: Option<&str>) {
+    let _guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    if let Ok(mut spec) = load_vm_spec(&config_path) {
+        match driver {
+            Some(driver) => spec.host_drivers.insert(bdf.to_string(), driver.to_string()),
+            None => spec.host_drivers.remove(bdf),
+        };
+        let _ = save_vm_spec(&config_path, &spec);
+    }
+}
+
+pub fn device_cli_arg(device: &DeviceSpec) -> String {
+    format!("path=/sys/bus/pci/devices/{}/,id={}", device.address, device.id)
+}
+
+// Check the boot devices and give them ids, they must be whole viable IOMMU groups
+pub fn build_boot_devices(hostpcis: &[HostPci]) -> Result<Vec<DeviceSpec>, String> {
+    let addresses: Vec<String> = hostpcis.iter()
+        .map(|pci| normalize_bdf(&pci.address))
+        .collect::<Result<_, _>>()?;
+    for bdf in addresses.iter() {
+        if !Path::new(&pci_device_path(bdf)).exists() {
+            return Err(format!("PCI device {} not found", bdf));
+        }
+    }
+    let addresses = resolve_iommu_groups(&addresses, false)?;
+
+    Ok(addresses.into_iter()
+        .enumerate()
+        .map(|(index, address)| DeviceSpec { id: format!("hostdev{}", index), address })
+        .collect())
+}
+
+// Claim the boot devices in the ledger, all of them or none
+pub fn reserve_boot_devices(vm_id: i16, devices: &[DeviceSpec], pci_ledger: &Arc<Mutex<PciLedger>>) 
+                            -> Result<(), String> {
+    let devices: Vec<(&str, &str)> = devices.iter()
+        .map(|device| (device.address.as_str(), device.id.as_str()))
+        .collect();
+    allocate_pcis(&devices, vm_id, pci_ledger)
+}
+
+// Validate the boot devices against the ledger and bind them to vfio-pci
+// before the VMM is launched
+pub fn prepare_boot_devices(vm_id: i16, pci_ledger: &Arc<Mutex<PciLedger>>) -> Result<(), String> {
+    let config_path = format!("../vms-config/{}", vm_id);
+    let spec = match load_vm_spec(&config_path) {
+        Ok(spec) => spec,
+        Err(_) => return Ok(()),
+    };
+    prepare_vm_mdevs(&spec.mdevs)?;
+    if spec.devices.is_empty() {
+        return Ok(());
+    }
+
+    let addresses: Vec<String> = spec.devices.iter().map(|device| device.address.clone()).collect();
+    resolve_iommu_groups(&addresses, false)?;
+    reserve_boot_devices(vm_id, &spec.devices, pci_ledger)?;
+
+    for device in spec.devices.iter() {
+        info!("Preparing the boot device {} as {}", device.address, device.id);
+        match bind_vfio(&device.address) {
+            Ok(Some(driver)) => record_host_driver(vm_id, &device.address, Some(&driver)),
+            Ok(None) => {}
+            Err(e) => return Err(format!("Cannot bind the device {} to vfio-pci: {}", device.address, e)),
+        }
+    }
+    Ok(())
+}
+
+pub fn add_pci_device(vm_id: i16, device_id: &str, retries: i16, 
+                        pci_ledger: &Arc<Mutex<PciLedger>>) -> Result<String, String> {
+    let bdf = normalize_bdf(device_id)?;
+    {
+        // Boot devices and devices added earlier already have a device id
+        let ledger = pci_ledger.lock().unwrap();
+        if let Some(allocation) = ledger.get(&bdf) {
+            if allocation.vm_id == vm_id && !allocation.device_id.is_empty() {
+                return Err(format!("The device {} is already attached to vm_id: {} as {}", 
+                                    bdf, vm_id, allocation.device_id));
+            }
+        }
+    }
+    // Only an allocation made here is released on failure, a reservation of the VM stays
+    let allocated = allocate_pci(&bdf, vm_id, pci_ledger)?;
+
+    let original_driver = match bind_vfio(&bdf) {
+        Ok(driver) => driver,
+        Err(e) => {
+            error!("Cannot bind the device {} to vfio-pci: {}", bdf, e);
+            if allocated {
+                release_pci(&bdf, vm_id, pci_ledger);
+            }
+            return Err(format!("Cannot bind the device {} to vfio-pci: {}", bdf, e));
+        }
+    };
+
+    let mut result: Result<String, String> = Err(format!("Cannot pass through the device {}", bdf));
     let mut retries = retries;
-    while retries > 0 && result == "None" {
+    while retries > 0 && result.is_err() {
         let api_socket = format!("/tmp/cloud-hypervisor{}.sock", vm_id);
         let output = Command::new("sudo")
             .arg("ch-remote")
             .arg("--api-socket")
             .arg(api_socket)
             .arg("add-device")
-            .arg(format!("path=/sys/bus/pci/devices/0000:{}/", device_id))
+            .arg(format!("path=/sys/bus/pci/devices/{}/", bdf))
             .output();
 
         match output {
             Ok(output) => {
                 if output.status.success() {
-                    result = String::from_utf8(output.stdout).unwrap();
-                    println!("Set the virtual machine configuration successfully.");
+                    result = Ok(String::from_utf8(output.stdout).unwrap());
+                    info!("Set the virtual machine configuration successfully.");
                 } else {
-                    eprintln!(
+                    let error = String::from_utf8_lossy(&output.stderr).to_string();
+                    error!(
                         "Command failed with exit code: {:?}\nError: {}",
                         output.status.code(),
-                        String::from_utf8_lossy(&output.stderr)
+                        error
                     );
                     retries -= 1;
-                    result = "None".to_string();
+                    result = Err(format!("Cannot pass through the device {}: {}", bdf, error.trim()));
                 }
             }
             Err(e) => {
-                eprintln!("Failed to execute command: {}", e);
+                error!("Failed to execute command: {}", e);
                 retries -= 1;
-                result = "None".to_string();
+                result = Err(format!("Failed to execute command: {}", e));
+            }
+        }
+    }
+
+    match result.as_ref() {
+        Ok(detail) => {
+            // Detail example is "{"id":"_vfio3","bdf":"0000:00:06.0"}"
+            let detail: Value = serde_json::from_str(detail).unwrap_or_default();
+            set_pci_device_id(&bdf, detail["id"].as_str().unwrap_or(""), pci_ledger);
+            if let Some(driver) = original_driver {
+                record_host_driver(vm_id, &bdf, Some(&driver));
+            }
+        }
+        Err(_) => {
+            if let Some(driver) = original_driver {
+                let _ = restore_driver(&bdf, Some(&driver));
+            }
+            if allocated {
+                release_pci(&bdf, vm_id, pci_ledger);
+            }
+        }
+    }
+
+    result
+}
+
+// Attach every reserved device, detaching the ones already added when one fails
+pub fn attach_pci_devices(vm_id: i16, devices: &[String], pci_ledger: &Arc<Mutex<PciLedger>>) 
+                            -> Result<Vec<String>, String> {
+    let mut attached: Vec<(String, String)> = Vec::new();
+    for bdf in devices {
+        match add_pci_device(vm_id, bdf, 3, pci_ledger) {
+            Ok(detail) => attached.push((bdf.clone(), detail)),
+            Err(e) => {
+                info!("Rolling back the passthrough of vm_id: {}", vm_id);
+                // A device the guest did not let go of stays reserved and bound to vfio-pci
+                let mut busy: Vec<&String> = Vec::new();
+                for (bdf, detail) in attached.iter() {
+                    let detail: Value = serde_json::from_str(detail).unwrap_or_default();
+                    let removed = match detail["id"].as_str() {
+                        Some(device_id) => remove_pci_device(vm_id, device_id).is_ok() 
+                            && wait_device_removed(vm_id, device_id, DEVICE_REMOVE_TIMEOUT),
+                        None => false,
+                    };
+                    if removed {
+                        release_pci_device(vm_id, bdf, pci_ledger);
+                    } else {
+                        error!("Cannot detach the device {} from vm_id: {}, keeping it reserved", bdf, vm_id);
+                        busy.push(bdf);
+                    }
+                }
+                for bdf in devices.iter().filter(|bdf| !busy.contains(bdf)) {
+                    release_pci(bdf, vm_id, pci_ledger);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(attached.into_iter().map(|(_, detail)| detail).collect())
+}
+
+// Hand a device detached from the VM back to its host driver
+pub fn release_pci_device(vm_id: i16, address: &str, pci_ledger: &Arc<Mutex<PciLedger>>) {
+    let bdf = match normalize_bdf(address) {
+        Ok(bdf) => bdf,
+        Err(e) => {
+            error!("Cannot release the device: {}", e);
+            return;
+        }
+    };
+    let config_path = format!("../vms-config/{}", vm_id);
+    let driver = match load_vm_spec(&config_path) {
+        Ok(spec) => spec.host_drivers.get(&bdf).cloned(),
+        Err(_) => None,
+    };
+
+    if let Some(driver) = driver {
+        if let Err(e) = restore_driver(&bdf, Some(&driver)) {
+            error!("Cannot restore the driver of {}: {}", bdf, e);
+        }
+        record_host_driver(vm_id, &bdf, None);
+    }
+    release_pci(&bdf, vm_id, pci_ledger);
+}
+
+// Boot devices stay reserved for the VM while it is stopped unless keep_boot is false
+pub fn release_vm_pci_devices(vm_id: i16, pci_ledger: &Arc<Mutex<PciLedger>>, keep_boot: bool) {
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut devices = find_vm_pcis(vm_id, pci_ledger);
+    if let Ok(spec) = load_vm_spec(&config_path) {
+        for bdf in spec.host_drivers.keys() {
+            if !devices.contains(bdf) {
+                devices.push(bdf.clone());
+            }
+        }
+        if keep_boot {
+            devices.retain(|bdf| !spec.devices.iter().any(|device| &device.address == bdf));
+        }
+    }
+
+    for bdf in devices {
+        release_pci_device(vm_id, &bdf, pci_ledger);
+    }
+}
+
+// Find the cloud-hypervisor device id (such as _vfio3) of a host device, from
+// the ledger first and then from the devices listed by vm.info
+pub fn resolve_pci_device_id(vm_id: i16, address: &str, pci_ledger: &Arc<Mutex<PciLedger>>) 
+                            -> Result<String, String> {
+    if !address.contains(':') {
+        return Ok(address.to_string());
+    }
+
+    let bdf = normalize_bdf(address)?;
+    {
+        let ledger = pci_ledger.lock().unwrap();
+        if let Some(allocation) = ledger.get(&bdf) {
+            if allocation.vm_id != vm_id {
+                return Err(format!("The device {} is assigned to vm_id: {}", bdf, allocation.vm_id));
+            }
+            if !allocation.device_id.is_empty() {
+                return Ok(allocation.device_id.clone());
             }
         }
     }
 
-    return result
+    let info: Value = serde_json::from_str(&get_vm_config(vm_id))
+        .map_err(|_| format!("Cannot get the config of vm_id: {}", vm_id))?;
+    info["config"]["devices"].as_array()
+        .and_then(|devices| devices.iter().find(|device| {
+            device["path"].as_str().unwrap_or("").trim_end_matches('/').ends_with(&bdf)
+        }))
+        .and_then(|device| device["id"].as_str())
+        .map(|id| id.to_string())
+        .ok_or(format!("The device {} is not attached to vm_id: {}", bdf, vm_id))
 }
 
-pub fn remove_pci_device(vm_id: i16, device_id: &str) -> String {
+pub fn remove_pci_device(vm_id: i16, device_id: &str) -> Result<String, String> {
     let api_socket = format!("/tmp/cloud-hypervisor{}.sock", vm_id);
     let output = Command::new("sudo")
         .arg("ch-remote")
         .arg("--api-socket")
         .arg(api_socket)
         .arg("remove-device")
-        .arg(format!("{}", device_id))
+        .arg(device_id)
         .output();
 
     match output {
         Ok(output) => {
             if output.status.success() {
                 let output_str = String::from_utf8(output.stdout).unwrap();
-                println!("Set the virtual machine configuration successfully.");
-                return output_str
+                info!("Set the virtual machine configuration successfully.");
+                Ok(output_str)
             } else {
-                eprintln!(
+                let error = String::from_utf8_lossy(&output.stderr).to_string();
+                error!(
                     "Command failed with exit code: {:?}\nError: {}",
                     output.status.code(),
-                    String::from_utf8_lossy(&output.stderr)
+                    error
                 );
-                return "None".to_string();
+                Err(format!("Cannot remove the device {}: {}", device_id, error.trim()))
             }
         }
         Err(e) => {
-            eprintln!("Failed to execute command: {}", e);
-            return "None".to_string();
+            error!("Failed to execute command: {}", e);
+            Err(format!("Failed to execute command: {}", e))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(vendor: Option<&str>, class: Option<&str>) -> PciFilter {
+        PciFilter {
+            vendor: vendor.map(String::from),
+            device: None,
+            class: class.map(String::from),
+            subclass: None,
+            driver: None,
+            iommu_group: None,
+            assigned: None,
+        }
+    }
+
+    #[test]
+    fn pci_filter_accepts_hex_ids() {
+        assert!(validate_pci_filter(&filter(None, None)).is_ok());
+        assert!(validate_pci_filter(&filter(Some("10de"), Some("03"))).is_ok());
+        assert!(validate_pci_filter(&filter(Some("0x8086"), Some("0x02"))).is_ok());
+    }
+
+    #[test]
+    fn pci_filter_rejects_invalid_ids() {
+        assert_eq!(validate_pci_filter(&filter(Some("nvidia"), None)),
+                   Err("invalid vendor id 'nvidia'".to_string()));
+        assert_eq!(validate_pci_filter(&filter(Some("10de0"), None)),
+                   Err("invalid vendor id '10de0'".to_string()));
+        assert_eq!(validate_pci_filter(&filter(None, Some("300"))),
+                   Err("invalid class '300'".to_string()));
+    }
+}
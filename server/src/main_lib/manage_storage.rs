@@ -0,0 +1,521 @@
+use std::{
+    sync::{Arc, Mutex},
+    path::Path,
+    process::Command,
+    fs::OpenOptions,
+    fs,
+};
+use nix::sys::statvfs::statvfs;
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::main_lib::structure::{MAXVM, VmStatus, VmSpec, DiskSpec, DiskRateLimit, StoragePool, 
+                                    StorageConfig, RequestQuotaData, RequestRateLimitData, 
+                                    RequestDiskData};
+use crate::main_lib::init_vm::{load_vm_spec, save_vm_spec, lock_vm_spec, list_vm_specs, write_vm_config};
+use crate::main_lib::cloud_init::{validate_hostname};
+
+const STORAGE_CONFIG: &str = "../storage-pools.json";
+pub const DEFAULT_POOL: &str = "default";
+use crate::main_lib::manage_vm::call_vmm_api;
+
+// Parse sizes like "512M", "20G", "1.5TiB" or a plain byte count
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse()
+        .map_err(|_| format!("'{}' is not a valid size", size))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("'{}' has an unknown size unit '{}'", size, unit.trim())),
+    };
+
+    let bytes = number * multiplier as f64;
+    if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+        return Err(format!("'{}' is too large", size));
+    }
+    let bytes = bytes as u64;
+    if bytes == 0 {
+        return Err(format!("'{}' must be greater than zero", size));
+    }
+    Ok(bytes)
+}
+
+pub fn get_free_space(path: &str) -> Result<u64, String> {
+    let stat = statvfs(Path::new(path)).map_err(|e| format!("statvfs {} failed: {}", path, e))?;
+    Ok(stat.blocks_available() * stat.fragment_size())
+}
+
+pub fn get_disk_size(path: &str) -> u64 {
+    fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+// Disks attached by write_vm_config, the cloud-init seed is managed by the controller
+pub fn default_disks(disk_dir: &str, url: &str) -> Vec<DiskSpec> {
+    let image = url.rsplit('/').next().unwrap_or("").split('.').next().unwrap_or("");
+    let root_path = format!("{}/{}.raw", disk_dir, image);
+    vec![DiskSpec { 
+        id: "disk0".to_string(), 
+        size: get_disk_size(&root_path), 
+        path: root_path, 
+        rate_limit: None,
+    }]
+}
+
+pub fn is_vm_running(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) -> bool {
+    let vm_vec = vm_vec.lock().unwrap();
+    vm_vec[vm_id as usize].status > 0
+}
+
+fn grow_offline(path: &str, size: u64) -> Result<(), String> {
+    if path.ends_with(".raw") || path.ends_with(".img") {
+        let file = OpenOptions::new().write(true).open(path)
+            .map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        return file.set_len(size).map_err(|e| format!("Cannot resize {}: {}", path, e));
+    }
+
+    match Command::new("qemu-img").arg("resize").arg(path).arg(size.to_string()).output() {
+        Ok(output) => {
+            if output.status.success() {
+                Ok(())
+            } else {
+                let error = String::from_utf8_lossy(&output.stderr).to_string();
+                error!("Command failed: {:?}", error);
+                Err(format!("qemu-img resize failed: {}", error.trim()))
+            }
+        }
+        Err(e) => {
+            error!("Failed to execute command: {}", e);
+            Err(format!("Failed to execute command: {}", e))
+        }
+    }
+}
+
+fn grow_online(vm_id: i16, disk_id: &str, size: u64) -> Result<(), String> {
+    let body = json!({"id": disk_id, "desired_size": size}).to_string();
+    call_vmm_api(vm_id, "PUT", "vm.resize-disk", Some(&body)).map(|_| ())
+}
+
+pub fn resize_disk(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16, disk_id: &str, size: &str) 
+                    -> Result<DiskSpec, String> {
+    if vm_id < 0 || vm_id as usize >= MAXVM {
+        return Err(format!("vm id {} is out of range", vm_id));
+    }
+    let new_size = parse_size(size)?;
+
+    let _guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut spec = load_vm_spec(&config_path)
+        .map_err(|e| format!("Cannot load the VM spec: {}", e))?;
+    let project = spec.project.clone();
+    let disk = spec.disks.iter_mut().find(|disk| disk.id == disk_id)
+        .ok_or(format!("Disk {} not found", disk_id))?;
+
+    let current_size = get_disk_size(&disk.path).max(disk.size);
+    if new_size <= current_size {
+        return Err(format!("Disk {} can only grow, current size is {} bytes", disk_id, current_size));
+    }
+    check_quota(vm_id, project.as_deref(), new_size - current_size)?;
+
+    let directory = Path::new(&disk.path).parent()
+        .map(|parent| parent.to_string_lossy().to_string())
+        .unwrap_or(".".to_string());
+    let free_space = get_free_space(&directory)?;
+    if new_size - current_size > free_space {
+        return Err(format!("Not enough free space: {} bytes needed, {} bytes available", 
+                            new_size - current_size, free_space));
+    }
+
+    if is_vm_running(vm_vec, vm_id) {
+        info!("Resizing the disk {} of vm_id: {} online", disk_id, vm_id);
+        grow_online(vm_id, disk_id, new_size)?;
+    } else {
+        info!("Resizing the disk {} of vm_id: {} offline", disk_id, vm_id);
+        grow_offline(&disk.path, new_size)?;
+    }
+
+    disk.size = new_size;
+    let disk = disk.clone();
+    save_vm_spec(&config_path, &spec).map_err(|e| format!("Cannot save the VM spec: {}", e))?;
+    Ok(disk)
+}
+
+fn default_pools() -> Vec<StoragePool> {
+    vec![
+        StoragePool { name: DEFAULT_POOL.to_string(), path: "../vms-config".to_string() },
+        StoragePool { name: "images".to_string(), path: "../os".to_string() },
+        StoragePool { name: "seeds".to_string(), path: "../storage".to_string() },
+    ]
+}
+
+pub fn load_storage_config() -> StorageConfig {
+    let mut config: StorageConfig = fs::read_to_string(STORAGE_CONFIG).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+
+    // The built-in pools are always available unless their path was overridden
+    for pool in default_pools() {
+        if !config.pools.iter().any(|existing| existing.name == pool.name) {
+            config.pools.push(pool);
+        }
+    }
+    config
+}
+
+pub fn save_storage_config(config: &StorageConfig) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(config)
+        .map_err(|e| format!("Cannot serialize the storage config: {}", e))?;
+    fs::write(STORAGE_CONFIG, content).map_err(|e| format!("Cannot write {}: {}", STORAGE_CONFIG, e))
+}
+
+pub fn find_pool(name: &str) -> Result<StoragePool, String> {
+    load_storage_config().pools.into_iter()
+        .find(|pool| pool.name == name)
+        .ok_or(format!("Storage pool {} not found", name))
+}
+
+// Directory holding the disks of a VM inside a pool
+pub fn vm_disk_dir(pool: &StoragePool, vm_id: i16) -> String {
+    format!("{}/{}", pool.path.trim_end_matches('/'), vm_id)
+}
+
+pub fn disks_total(spec: &VmSpec) -> u64 {
+    spec.disks.iter().map(|disk| disk.size).sum()
+}
+
+fn pool_info(pool: &StoragePool, specs: &[VmSpec]) -> Value {
+    let (capacity, free) = match statvfs(Path::new(&pool.path)) {
+        Ok(stat) => (stat.blocks() * stat.fragment_size(),
+                     stat.blocks_available() * stat.fragment_size()),
+        Err(e) => {
+            error!("statvfs {} failed: {}", pool.path, e);
+            (0, 0)
+        }
+    };
+
+    let pool_specs: Vec<&VmSpec> = specs.iter().filter(|spec| spec.pool == pool.name).collect();
+    json!({
+        "name": pool.name,
+        "path": pool.path,
+        "capacity": capacity,
+        "free": free,
+        "used": capacity.saturating_sub(free),
+        "allocated": pool_specs.iter().map(|spec| disks_total(spec)).sum::<u64>(),
+        "vms": pool_specs.iter().map(|spec| spec.vm_id).collect::<Vec<i16>>(),
+    })
+}
+
+pub fn list_pools() -> Vec<Value> {
+    let specs = list_vm_specs();
+    load_storage_config().pools.iter().map(|pool| pool_info(pool, &specs)).collect()
+}
+
+pub fn get_pool(name: &str) -> Result<Value, String> {
+    let pool = find_pool(name)?;
+    Ok(pool_info(&pool, &list_vm_specs()))
+}
+
+pub fn add_pool(name: &str, path: &str) -> Result<Value, String> {
+    validate_hostname(name).map_err(|_| format!("'{}' is not a valid pool name", name))?;
+    let mut config = load_storage_config();
+    if config.pools.iter().any(|pool| pool.name == name) {
+        return Err(format!("Storage pool {} already exists", name));
+    }
+
+    fs::create_dir_all(path).map_err(|e| format!("Cannot create {}: {}", path, e))?;
+    let pool = StoragePool { name: name.to_string(), path: path.to_string() };
+    config.pools.push(pool.clone());
+    save_storage_config(&config)?;
+    Ok(pool_info(&pool, &list_vm_specs()))
+}
+
+pub fn remove_pool(name: &str) -> Result<(), String> {
+    if default_pools().iter().any(|pool| pool.name == name) {
+        return Err(format!("Storage pool {} is built in and cannot be removed", name));
+    }
+    if list_vm_specs().iter().any(|spec| spec.pool == name) {
+        return Err(format!("Storage pool {} is still used by VMs", name));
+    }
+
+    let mut config = load_storage_config();
+    let original_len = config.pools.len();
+    config.pools.retain(|pool| pool.name != name);
+    if config.pools.len() == original_len {
+        return Err(format!("Storage pool {} not found", name));
+    }
+    save_storage_config(&config)
+}
+
+pub fn get_quotas() -> Value {
+    let config = load_storage_config();
+    let specs = list_vm_specs();
+    let projects: Vec<Value> = config.project_quotas.iter().map(|(project, quota)| {
+        let used: u64 = specs.iter()
+            .filter(|spec| spec.project.as_deref() == Some(project.as_str()))
+            .map(disks_total)
+            .sum();
+        json!({"project": project, "quota": quota, "used": used})
+    }).collect();
+
+    json!({
+        "vm_quota": config.vm_quota,
+        "project_quotas": projects,
+    })
+}
+
+pub fn set_quotas(payload: &RequestQuotaData) -> Result<Value, String> {
+    let mut config = load_storage_config();
+    config.vm_quota = match payload.vm_quota.as_deref() {
+        Some(quota) => Some(parse_size(quota)?),
+        None => None,
+    };
+
+    config.project_quotas.clear();
+    for (project, quota) in payload.project_quotas.iter() {
+        validate_hostname(project).map_err(|_| format!("'{}' is not a valid project name", project))?;
+        config.project_quotas.insert(project.clone(), parse_size(quota)?);
+    }
+
+    save_storage_config(&config)?;
+    Ok(get_quotas())
+}
+
+// Check the VM and project quotas before allocating extra bytes for a VM
+pub fn check_quota(vm_id: i16, project: Option<&str>, extra: u64) -> Result<(), String> {
+    let config = load_storage_config();
+    let specs = list_vm_specs();
+
+    if let Some(quota) = config.vm_quota {
+        let used: u64 = specs.iter().filter(|spec| spec.vm_id == vm_id).map(disks_total).sum();
+        if used + extra > quota {
+            return Err(format!("VM quota exceeded: {} bytes used, {} requested, quota is {} bytes", 
+                                used, extra, quota));
+        }
+    }
+
+    if let Some(project) = project {
+        if let Some(quota) = config.project_quotas.get(project) {
+            let used: u64 = specs.iter()
+                .filter(|spec| spec.project.as_deref() == Some(project))
+                .map(disks_total)
+                .sum();
+            if used + extra > *quota {
+                return Err(format!("Project {} quota exceeded: {} bytes used, {} requested, \
+                                    quota is {} bytes", project, used, extra, quota));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Quota violations are refused (403), a pool without room is a capacity conflict (409)
+pub fn check_storage(vm_id: i16, project: Option<&str>, pool: &StoragePool, needed: u64) 
+                    -> Result<(), (u16, String)> {
+    let free_space = get_free_space(&pool.path).unwrap_or(0);
+    storage_verdict(check_quota(vm_id, project, needed), pool, needed, free_space)
+}
+
+fn storage_verdict(quota: Result<(), String>, pool: &StoragePool, needed: u64, free_space: u64) 
+                    -> Result<(), (u16, String)> {
+    quota.map_err(|e| (403, e))?;
+    if needed > free_space {
+        return Err((409, format!("Not enough free space in pool {}: {} bytes needed, {} bytes available", 
+                                 pool.name, needed, free_space)));
+    }
+    Ok(())
+}
+
+pub fn parse_rate_limit(payload: &RequestRateLimitData) -> Result<DiskRateLimit, String> {
+    let bandwidth = match payload.bandwidth.as_deref() {
+        Some(bandwidth) => Some(parse_size(bandwidth)?),
+        None => None,
+    };
+    if payload.iops == Some(0) {
+        return Err("iops must be greater than zero".to_string());
+    }
+    Ok(DiskRateLimit { bandwidth, iops: payload.iops })
+}
+
+// Token buckets refilled every second so the sizes are per second limits
+fn rate_limit_args(rate_limit: &DiskRateLimit) -> String {
+    let mut args = String::new();
+    if let Some(bandwidth) = rate_limit.bandwidth {
+        args.push_str(&format!(",bw_size={},bw_refill_time=1000", bandwidth));
+    }
+    if let Some(iops) = rate_limit.iops {
+        args.push_str(&format!(",ops_size={},ops_refill_time=1000", iops));
+    }
+    args
+}
+
+pub fn disk_cli_arg(disk: &DiskSpec) -> String {
+    let limits = disk.rate_limit.as_ref().map(rate_limit_args).unwrap_or_default();
+    format!("path={},id={}{}", disk.path, disk.id, limits)
+}
+
+fn disk_api_config(disk: &DiskSpec) -> Value {
+    let mut config = json!({"path": disk.path, "id": disk.id});
+    if let Some(rate_limit) = disk.rate_limit.as_ref() {
+        let bucket = |size: Option<u64>| size.map(|size| json!({"size": size, "refill_time": 1000}));
+        config["rate_limiter_config"] = json!({
+            "bandwidth": bucket(rate_limit.bandwidth),
+            "ops": bucket(rate_limit.iops),
+        });
+    }
+    config
+}
+
+pub fn attach_disk(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16, payload: &RequestDiskData) 
+                    -> Result<DiskSpec, String> {
+    if vm_id < 0 || vm_id as usize >= MAXVM {
+        return Err(format!("vm id {} is out of range", vm_id));
+    }
+    validate_hostname(&payload.id).map_err(|_| format!("'{}' is not a valid disk id", payload.id))?;
+    let size = parse_size(&payload.size)?;
+    let rate_limit = payload.rate_limit.as_ref().map(parse_rate_limit).transpose()?;
+
+    let _guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut spec = load_vm_spec(&config_path)
+        .map_err(|e| format!("Cannot load the VM spec: {}", e))?;
+    if payload.id == "seed" || spec.disks.iter().any(|disk| disk.id == payload.id) {
+        return Err(format!("Disk {} already exists", payload.id));
+    }
+
+    let pool = find_pool(&spec.pool)?;
+    let disk_dir = vm_disk_dir(&pool, vm_id);
+    check_quota(vm_id, spec.project.as_deref(), size)?;
+    let free_space = get_free_space(&pool.path)?;
+    if size > free_space {
+        return Err(format!("Not enough free space in pool {}: {} bytes needed, {} bytes available", 
+                            pool.name, size, free_space));
+    }
+
+    // New disks are sparse raw files
+    fs::create_dir_all(&disk_dir).map_err(|e| format!("Cannot create {}: {}", disk_dir, e))?;
+    let disk = DiskSpec { 
+        id: payload.id.clone(), 
+        path: format!("{}/{}.raw", disk_dir, payload.id), 
+        size, 
+        rate_limit,
+    };
+    let file = OpenOptions::new().write(true).create_new(true).open(&disk.path)
+        .map_err(|e| format!("Cannot create {}: {}", disk.path, e))?;
+    file.set_len(size).map_err(|e| format!("Cannot resize {}: {}", disk.path, e))?;
+
+    if is_vm_running(vm_vec, vm_id) {
+        info!("Hot adding the disk {} to vm_id: {}", disk.id, vm_id);
+        let body = disk_api_config(&disk).to_string();
+        if let Err(e) = call_vmm_api(vm_id, "PUT", "vm.add-disk", Some(&body)) {
+            let _ = fs::remove_file(&disk.path);
+            return Err(e);
+        }
+    }
+
+    spec.disks.push(disk.clone());
+    save_vm_spec(&config_path, &spec).map_err(|e| format!("Cannot save the VM spec: {}", e))?;
+    write_vm_config(&config_path, &spec).map_err(|e| format!("Cannot write the VM config: {}", e))?;
+    Ok(disk)
+}
+
+// Returns the disk and whether the new limits only apply after the next boot
+pub fn set_disk_rate_limit(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16, disk_id: &str, 
+                            payload: &RequestRateLimitData) -> Result<(DiskSpec, bool), String> {
+    if vm_id < 0 || vm_id as usize >= MAXVM {
+        return Err(format!("vm id {} is out of range", vm_id));
+    }
+    let rate_limit = parse_rate_limit(payload)?;
+    let rate_limit = if rate_limit.bandwidth.is_none() && rate_limit.iops.is_none() {
+        None
+    } else {
+        Some(rate_limit)
+    };
+
+    let _guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut spec = load_vm_spec(&config_path)
+        .map_err(|e| format!("Cannot load the VM spec: {}", e))?;
+    let index = spec.disks.iter().position(|disk| disk.id == disk_id)
+        .ok_or(format!("Disk {} not found", disk_id))?;
+    spec.disks[index].rate_limit = rate_limit;
+    let disk = spec.disks[index].clone();
+
+    // cloud-hypervisor cannot change the limiter of a live disk and re-plugging it would pull
+    // the block device from under the guest, so a running VM gets the limits on its next boot
+    let pending = is_vm_running(vm_vec, vm_id);
+
+    save_vm_spec(&config_path, &spec).map_err(|e| format!("Cannot save the VM spec: {}", e))?;
+    write_vm_config(&config_path, &spec).map_err(|e| format!("Cannot write the VM config: {}", e))?;
+    Ok((disk, pending))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> StoragePool {
+        StoragePool { name: "default".to_string(), path: "/var/lib/chv".to_string() }
+    }
+
+    #[test]
+    fn storage_quota_violation_is_forbidden() {
+        let quota = Err("VM quota exceeded".to_string());
+        assert_eq!(storage_verdict(quota, &pool(), 10, 100), Err((403, "VM quota exceeded".to_string())));
+    }
+
+    #[test]
+    fn storage_without_room_is_a_conflict() {
+        let (code, e) = storage_verdict(Ok(()), &pool(), 200, 100).unwrap_err();
+        assert_eq!(code, 409);
+        assert!(e.contains("pool default"));
+        assert_eq!(storage_verdict(Ok(()), &pool(), 100, 100), Ok(()));
+    }
+
+    #[test]
+    fn parse_size_plain_bytes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size(" 4096 "), Ok(4096));
+    }
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("1K"), Ok(1 << 10));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("20G"), Ok(20 << 30));
+        assert_eq!(parse_size("20GB"), Ok(20 << 30));
+        assert_eq!(parse_size("2TiB"), Ok(2 << 40));
+        assert_eq!(parse_size("1.5g"), Ok(3 << 29));
+        assert_eq!(parse_size("10 G"), Ok(10 << 30));
+    }
+
+    #[test]
+    fn parse_size_rejects_zero_and_empty() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("   ").is_err());
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("0G").is_err());
+        assert!(parse_size("0.0000001K").is_err());
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_input() {
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1G").is_err());
+        assert!(parse_size("1.2.3G").is_err());
+        assert!(parse_size("12X").is_err());
+    }
+
+    #[test]
+    fn parse_size_rejects_overflow() {
+        assert!(parse_size("16777216T").is_err());
+        assert!(parse_size("99999999999999999999").is_err());
+        assert_eq!(parse_size("16777215T"), Ok(16777215u64 << 40));
+    }
+}
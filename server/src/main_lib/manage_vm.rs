@@ -1,47 +1,150 @@
 use crate::main_lib::structure::{
     MAXVM,
     VmStatus,
+    VmHealth,
+    MonitorStats,
+    PciLedger,
 };
 
 use std::{
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    thread,
     process::Command,
     ffi::OsStr,
+    path::Path,
     time::Duration,
     fs,
-    thread,
 };
 
 use crate::main_lib::structure::{mark_vm_stop};
-use sysinfo::System;
+use crate::main_lib::logging::{run_logged};
+use crate::main_lib::console::{spawn_console_capture};
+use crate::main_lib::init_vm::{load_vm_spec};
+use crate::main_lib::manage_pci::{prepare_boot_devices, release_vm_pci_devices};
+use crate::main_lib::mdev::{destroy_vm_mdevs};
+use crate::main_lib::health::{check_vm_health, unix_now};
+use crate::main_lib::vm_stats::{collect_vm_stats, record_stats_sample};
+use crate::main_lib::operations::{OperationList, OperationState, find_operation, push_operation_error, 
+                                    finish_operation};
+use sysinfo::{ProcessesToUpdate, System};
+use tracing::{error, info, info_span};
+use tokio::{sync::Semaphore, task::{self, JoinSet}, time::{sleep, timeout, Instant}};
 // use sha1::{Sha1, Digest};
 
 const INTERVAL: u64 = 10000;
+const VMM_API_TIMEOUT: Duration = Duration::from_secs(30);
+// Bounds of a monitor run, a probe over the deadline counts as unresponsive
+const MONITOR_PARALLELISM: usize = 16;
+const PROBE_DEADLINE: Duration = Duration::from_secs(8);
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Hot unplug completes once the guest acknowledges it
+pub const DEVICE_REMOVE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// A killed VMM closes its vfio fds once the process has exited
+const PROCESS_EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+// The VMM has to answer on its API socket within this time after launch
+const VMM_BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+const VMM_PING_TIMEOUT: Duration = Duration::from_secs(2);
+// Upper bound in seconds of the wait-ready option of create
+pub const MAX_WAIT_READY: u64 = 1800;
 
-pub fn start_vm(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16, config_path: &str) -> i32 {
-    {
+// Report the boot once the VMM answers on its API socket, or a failure when it exits first
+fn watch_vmm_boot(vm_id: i16, exited: Arc<AtomicBool>, 
+                    on_boot: impl FnOnce(Result<(), String>) + Send + 'static) {
+    let span = tracing::Span::current();
+    thread::spawn(move || {
+        let _entered = span.enter();
+        let started = std::time::Instant::now();
+        let booted = loop {
+            if call_vmm_api_with_timeout(vm_id, "GET", "vmm.ping", None, VMM_PING_TIMEOUT).is_ok() {
+                info!("The VMM API is up");
+                break Ok(());
+            }
+            if exited.load(Ordering::SeqCst) {
+                break Err("The VMM exited before its API answered".to_string());
+            }
+            if started.elapsed() >= VMM_BOOT_TIMEOUT {
+                break Err(format!("The VMM API did not answer after {} seconds", VMM_BOOT_TIMEOUT.as_secs()));
+            }
+            thread::sleep(READY_POLL_INTERVAL);
+        };
+        on_boot(booted);
+    });
+}
+
+// Runs until the VM exits, on_boot is called as soon as the VMM is up or failed to come up
+pub fn start_vm(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16, config_path: &str, 
+                on_boot: impl FnOnce(Result<(), String>) + Send + 'static) -> i32 {
+    let launch = {
         let mut vm_vec = vm_vec.lock().unwrap();
         vm_vec[vm_id as usize].status = 1;
+        vm_vec[vm_id as usize].ready_at = None;
+        vm_vec[vm_id as usize].launch += 1;
+        vm_vec[vm_id as usize].launch
+    };
+    // The script runs the hypervisor in the foreground, its output goes to the VM span until it exits
+    let span = info_span!("vm", vm_id);
+    let _entered = span.enter();
+    spawn_console_capture(vm_vec, vm_id);
+    let exited = Arc::new(AtomicBool::new(false));
+    watch_vmm_boot(vm_id, Arc::clone(&exited), on_boot);
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(format!("sudo sh {}/vm-config.sh", config_path));
+    let status = run_logged("cloud-hypervisor", &mut command);
+    exited.store(true, Ordering::SeqCst);
+
+    // The VMM exited, the guest is no longer ready unless the VM was launched again meanwhile
+    {
+        let mut vm_vec = vm_vec.lock().unwrap();
+        if vm_vec[vm_id as usize].launch == launch {
+            vm_vec[vm_id as usize].status = if status.is_err() { -1 } else { 0 };
+            vm_vec[vm_id as usize].ready_at = None;
+        }
     }
-    match Command::new("sh").arg("-c")
-        .arg(format!("sudo sh {}/vm-config.sh", config_path))
-        .output() {
-            Ok(output) => {
-                if !output.status.success() {
-                    eprintln!("Command failed: {:?}", String::from_utf8_lossy(&output.stderr));
+    match status {
+            Ok(status) => {
+                if !status.success() {
+                    error!(code = status.code(), "Command failed");
                     return -1;
                 }
             }
             Err(e) => {
-                eprintln!("Failed to execute command: {}", e);
-                {
-                    let mut vm_vec = vm_vec.lock().unwrap();
-                    vm_vec[vm_id as usize].status = -1;
-                }
+                error!("Failed to execute command: {}", e);
                 return -1;
             }
         }
-    return 1;
+    1
+}
+
+// Boot the VM of a started operation and run it until the VMM exits, the operation is
+// over once the VMM is up. Fails when the boot devices cannot be prepared
+pub fn run_vm_operation(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, pci_ledger: &Arc<Mutex<PciLedger>>, 
+                        operations: &OperationList, vm_id: i16, operation_id: &str) -> Result<(), String> {
+    if let Err(e) = prepare_boot_devices(vm_id, pci_ledger) {
+        error!("Cannot prepare the boot devices.");
+        push_operation_error(operation_id, &e, operations);
+        finish_operation(operation_id, operations);
+        return Err(e);
+    }
+    // A later stop is not a boot failure
+    let config_path = format!("../vms-config/{}", vm_id);
+    let operations = Arc::clone(operations);
+    let operation_id = operation_id.to_string();
+    start_vm(vm_vec, vm_id, &config_path, move |booted| {
+        if let Err(e) = booted {
+            error!("Cannot boot the VM: {}", e);
+            push_operation_error(&operation_id, &e, &operations);
+        }
+        finish_operation(&operation_id, &operations);
+    });
+
+    // A VMM that exited on its own took its hot added devices with it, unless the VM
+    // was launched again they go back to the host
+    let relaunched = vm_vec.lock().unwrap()[vm_id as usize].status > 0;
+    if !relaunched {
+        release_vm_pci_devices(vm_id, pci_ledger, true);
+    }
+    Ok(())
 }
 
 pub fn shutdown_vm(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
@@ -62,7 +165,7 @@ pub fn shutdown_vm(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
             if output.status.success() {
                 let _ = String::from_utf8(output.stdout).unwrap();
             } else {
-                eprintln!(
+                error!(
                     "Command failed with exit code: {:?}\nError: {}",
                     output.status.code(),
                     String::from_utf8_lossy(&output.stderr)
@@ -70,15 +173,37 @@ pub fn shutdown_vm(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
             }
         }
         Err(e) => {
-            eprintln!("Failed to execute command: {}", e);
+            error!("Failed to execute command: {}", e);
         }
     }
 }
 
-pub fn force_terminate(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
-    let vm_vec = vm_vec.lock().unwrap();
-    let pid_str: String = (vm_vec[vm_id as usize].process_id).clone().into();
-    if pid_str != "None" {
+// A zombie has already released its files, only the parent still has to reap it
+fn process_exited(pid: &str) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat.rsplit_once(')')
+            .map(|(_, fields)| fields.trim_start().starts_with('Z'))
+            .unwrap_or(false),
+        Err(_) => true,
+    }
+}
+
+fn wait_process_exit(pid: &str, timeout: Duration) -> bool {
+    let started = std::time::Instant::now();
+    while !process_exited(pid) {
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+pub fn force_terminate(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, pci_ledger: &Arc<Mutex<PciLedger>>, 
+                        vm_id: i16) {
+    let pid_str: String = (vm_vec.lock().unwrap()[vm_id as usize].process_id).clone().into();
+    let mut exited = true;
+    if !pid_str.is_empty() && pid_str != "None" {
         let kill_status = Command::new("sudo")
             .arg("kill")
             .arg("-9")
@@ -87,13 +212,25 @@ pub fn force_terminate(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
             .expect("Failed to execute kill command");
 
         if kill_status.success() {
-            println!("The process {} was terminated", pid_str);
-            mark_vm_stop(vm_vec, vm_id as usize);
+            info!("The process {} was terminated", pid_str);
         } else {
-            println!("There is a problem while removing and end the process");
+            info!("There is a problem while removing and end the process");
+        }
+
+        exited = wait_process_exit(&pid_str, PROCESS_EXIT_TIMEOUT);
+        if exited {
+            mark_vm_stop(vm_vec.lock().unwrap(), vm_id as usize);
+        } else {
+            error!("The process {} of vm_id: {} is still running", pid_str, vm_id);
         }
     } else {
-        println!("There is no process for vm id: {}", vm_id);
+        info!("There is no process for vm id: {}", vm_id);
+    }
+
+    // Hot added devices go away with the VMM process, boot devices stay reserved.
+    // While the process lives it still holds the devices, they stay in the ledger
+    if exited {
+        release_vm_pci_devices(vm_id, pci_ledger, true);
     }
 
     let remove_api_status = Command::new("sudo")
@@ -104,7 +241,7 @@ pub fn force_terminate(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
             .expect("Failed to execute remove command");
     
     if remove_api_status.success() {
-        println!("The api socket was removed");
+        info!("The api socket was removed");
     }
 }
 
@@ -120,30 +257,98 @@ pub fn get_vm_config(vm_id: i16) -> String {
     match output {
         Ok(output) => {
             if output.status.success() {
-                let output_str = String::from_utf8(output.stdout).unwrap();
-                return output_str
+                String::from_utf8(output.stdout).unwrap()
             } else {
-                eprintln!(
+                error!(
                     "Command failed with exit code: {:?}\nError: {}",
                     output.status.code(),
                     String::from_utf8_lossy(&output.stderr)
                 );
-                return "Error: Can not get the config for this vm".to_string()
+                "Error: Can not get the config for this vm".to_string()
             }
         }
         Err(e) => {
-            eprintln!("Failed to execute command: {}", e);
-            return "Error: Can not get the config for this vm".to_string()
+            error!("Failed to execute command: {}", e);
+            "Error: Can not get the config for this vm".to_string()
         }
     }
 }
 
-pub fn get_vm_proc_id(vm_id: i16) -> String {
-    let mut s = System::new_all();
-    s.refresh_all();
+fn vm_has_device(info: &serde_json::Value, device_id: &str) -> bool {
+    info["config"].as_object()
+        .map(|config| config.values()
+            .filter_map(|value| value.as_array())
+            .flatten()
+            .any(|device| device["id"] == device_id))
+        .unwrap_or(false)
+}
+
+// Poll vm.info until the device id is gone from the VM config, false when it is still there after the timeout
+pub fn wait_device_removed(vm_id: i16, device_id: &str, timeout: Duration) -> bool {
+    let started = std::time::Instant::now();
+    loop {
+        let info = call_vmm_api(vm_id, "GET", "vm.info", None).ok()
+            .and_then(|info| serde_json::from_str::<serde_json::Value>(&info).ok());
+        if let Some(info) = info {
+            if !vm_has_device(&info, device_id) {
+                return true;
+            }
+        }
+        if started.elapsed() >= timeout {
+            error!("The device {} of vm_id: {} was not released after {} seconds",
+                   device_id, vm_id, timeout.as_secs());
+            return false;
+        }
+        std::thread::sleep(DEVICE_POLL_INTERVAL);
+    }
+}
+
+// Call the cloud-hypervisor REST API through the VM api socket
+pub fn call_vmm_api(vm_id: i16, method: &str, endpoint: &str, body: Option<&str>) 
+                    -> Result<String, String> {
+    call_vmm_api_with_timeout(vm_id, method, endpoint, body, VMM_API_TIMEOUT)
+}
+
+pub fn call_vmm_api_with_timeout(vm_id: i16, method: &str, endpoint: &str, body: Option<&str>, 
+                                    timeout: Duration) -> Result<String, String> {
+    let api_socket = format!("/tmp/cloud-hypervisor{}.sock", vm_id);
+    let mut command = Command::new("sudo");
+    command.arg("curl")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--max-time")
+        .arg(timeout.as_secs().max(1).to_string())
+        .arg("--unix-socket")
+        .arg(api_socket)
+        .arg("-X")
+        .arg(method)
+        .arg(format!("http://localhost/api/v1/{}", endpoint));
+    if let Some(body) = body {
+        command.arg("-H").arg("Content-Type: application/json").arg("-d").arg(body);
+    }
 
+    match command.output() {
+        Ok(output) => {
+            if output.status.success() {
+                Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            } else {
+                let error = String::from_utf8_lossy(&output.stderr).to_string();
+                error!("Command failed with exit code: {:?}\nError: {}", output.status.code(), error);
+                Err(format!("{} {} failed: {}", method, endpoint, error.trim()))
+            }
+        }
+        Err(e) => {
+            error!("Failed to execute command: {}", e);
+            Err(format!("Failed to execute command: {}", e))
+        }
+    }
+}
+
+pub fn get_vm_proc_id(vm_id: i16) -> String {
     let search_command = format!("/tmp/cloud-hypervisor{}.sock", vm_id); 
-    let s = System::new_all();
+    let mut s = System::new();
+    s.refresh_processes(ProcessesToUpdate::All, true);
     for process in s.processes_by_name(OsStr::new("cloud-h")) {
         // println!("Process Info:\n\
         //         PID: {}\n\
@@ -158,73 +363,171 @@ pub fn get_vm_proc_id(vm_id: i16) -> String {
             return process.pid().to_string();
         }
     }
-    return "None".to_string();
+    "None".to_string()
 }
 
-pub fn delete_vm(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16) {
-    force_terminate(&vm_vec, vm_id);
+pub fn delete_vm(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, pci_ledger: &Arc<Mutex<PciLedger>>, vm_id: i16) {
+    force_terminate(vm_vec, pci_ledger, vm_id);
+    release_vm_pci_devices(vm_id, pci_ledger, false);
+    destroy_vm_mdevs(vm_id);
     let config_path = format!("../vms-config/{}", vm_id);
     let storage_path = format!("../storage/cloudinit{}.img", vm_id);
+
+    // Disks may live in another storage pool
+    if let Ok(spec) = load_vm_spec(&config_path) {
+        for disk in spec.disks.iter() {
+            let _ = fs::remove_file(&disk.path);
+            if let Some(disk_dir) = Path::new(&disk.path).parent() {
+                let _ = fs::remove_dir(disk_dir);
+            }
+        }
+    }
     let _ = fs::remove_dir_all(config_path);
     let _ = fs::remove_file(storage_path);
 }
 
-pub fn resize_storage(config_path: &str, url: &str, storage: &str) {
-    let image = url.rsplit('/').next().unwrap_or("").split('.').nth(0).unwrap_or("");
-    println!("{}", format!("qemu-img resize {}/{}.raw +{}", config_path, image, storage));
+pub fn resize_storage(disk_dir: &str, url: &str, storage: &str) {
+    let image = url.rsplit('/').next().unwrap_or("").split('.').next().unwrap_or("");
+    info!("qemu-img resize {}/{}.raw +{}", disk_dir, image, storage);
     match Command::new("sh").arg("-c")
         .arg(format!("qemu-img resize {}/{}.raw +{}", 
-                        config_path, image, storage))
+                        disk_dir, image, storage))
         .output() {
             Ok(output) => {
                 if !output.status.success() {
-                    eprintln!("Command failed: {:?}", String::from_utf8_lossy(&output.stderr));
+                    error!(stderr = %String::from_utf8_lossy(&output.stderr).trim(), "Command failed");
                 }
             }
-            Err(e) => eprintln!("Failed to execute command: {}", e),
+            Err(e) => error!("Failed to execute command: {}", e),
         }
 }
 
-pub async fn monitor_vms(vm_vec: &Arc<Mutex<Vec<VmStatus>>>) {
+// Map the health check to the VM status, the VM is stopped once the VMM
+// has been gone for lost_signal_count checks
+fn apply_vm_health(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: usize, pid: String, health: VmHealth) {
+    let mut vm_vec = vm_vec.lock().unwrap();
+    // Stopped or deleted while it was being checked
+    if vm_vec[vm_id].status <= 0 {
+        return;
+    }
+
+    let lost = match health.vmm_state.as_str() {
+        "running" => { vm_vec[vm_id].status = 2; false }
+        "paused" => { vm_vec[vm_id].status = 5; false }
+        "created" => { vm_vec[vm_id].status = 1; false }
+        "shutdown" => { vm_vec[vm_id].status = 4; false }
+        "unresponsive" => {
+            if vm_vec[vm_id].status != 1 {
+                vm_vec[vm_id].status = 3;
+            }
+            true
+        }
+        _ => true,
+    };
+    vm_vec[vm_id].process_id = pid.into();
+    vm_vec[vm_id].health = health;
+
+    if !lost {
+        vm_vec[vm_id].lost_signal_count = 3;
+    } else if vm_vec[vm_id].lost_signal_count > 0 {
+        vm_vec[vm_id].lost_signal_count -= 1;
+    } else {
+        mark_vm_stop(vm_vec, vm_id);
+    }
+}
+
+async fn probe_vm(vm_vec: Arc<Mutex<Vec<VmStatus>>>, semaphore: Arc<Semaphore>, 
+                    vm_id: usize, pid: String) -> bool {
+    let _permit = semaphore.acquire_owned().await;
+    let started = Instant::now();
+    let last_pid = pid.clone();
+    let probe = task::spawn_blocking(move || check_vm_health(vm_id as i16, &pid));
+
+    let (pid, mut health, timed_out) = match timeout(PROBE_DEADLINE, probe).await {
+        Ok(Ok((pid, health))) => (pid, health, false),
+        Ok(Err(e)) => {
+            error!("The health check of vm_id: {} failed: {}", vm_id, e);
+            (last_pid, VmHealth { vmm_state: "unresponsive".to_string(), ..Default::default() }, false)
+        }
+        Err(_) => {
+            error!("The health check of vm_id: {} timed out", vm_id);
+            (last_pid, VmHealth { vmm_state: "unresponsive".to_string(), ..Default::default() }, true)
+        }
+    };
+    health.checked_at = unix_now();
+    health.probe_latency_ms = started.elapsed().as_millis() as u64;
+    let running = health.vmm_state == "running";
+    apply_vm_health(&vm_vec, vm_id, pid.clone(), health);
+
+    // Sample the usage of running VMs for the stats history
+    if running {
+        let stats = task::spawn_blocking(move || collect_vm_stats(vm_id as i16, &pid));
+        if let Ok(Ok(Ok(stats))) = timeout(PROBE_DEADLINE, stats).await {
+            record_stats_sample(&vm_vec, vm_id, &stats);
+        }
+    }
+    timed_out
+}
+
+pub async fn monitor_vms(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, monitor_stats: &Arc<Mutex<MonitorStats>>) {
+    let semaphore = Arc::new(Semaphore::new(MONITOR_PARALLELISM));
     loop {
         // Sleeping
-        let wait_delay = Duration::from_millis(INTERVAL);
-        thread::sleep(wait_delay);
-
-        // Find the all vm_id
-        for vm_id in 0..MAXVM {
-
-            // VM: Running case -> mark no signal
-            let mut vm_vec = vm_vec.lock().unwrap();
-            if vm_vec[vm_id].status > 0 {
-                let addr = format!("192.168.{}.2", vm_id).parse().unwrap();
-                let data = [1,2,3];
-                let timeout = Duration::from_secs(1);
-                let options = ping_rs::PingOptions { ttl: 128, dont_fragment: true };
-                let result = ping_rs::send_ping(&addr, timeout, &data, Some(&options));
-                match result {
-                    Ok(_) => {
-                        vm_vec[vm_id].status = 2;
-                        if vm_vec[vm_id].process_id == "".into() {
-                            vm_vec[vm_id].process_id = get_vm_proc_id(vm_id.try_into().unwrap()).into();
-                        }
-                    },
-                    Err(_) => {
-                        if vm_vec[vm_id].status != 1 {
-                            vm_vec[vm_id].status = 3;
-                        }
-                    }
-                }
+        sleep(Duration::from_millis(INTERVAL)).await;
+        let started = Instant::now();
+
+        // Take the VMs to check so the lock is not held while probing
+        let targets: Vec<(usize, String)> = {
+            let vm_vec = vm_vec.lock().unwrap();
+            (0..MAXVM)
+                .filter(|vm_id| vm_vec[*vm_id].status > 0)
+                .map(|vm_id| (vm_id, vm_vec[vm_id].process_id.to_string()))
+                .collect()
+        };
+
+        let vms_checked = targets.len();
+        let mut probes = JoinSet::new();
+        for (vm_id, pid) in targets {
+            probes.spawn(probe_vm(Arc::clone(vm_vec), Arc::clone(&semaphore), vm_id, pid));
+        }
+
+        let mut probes_timed_out = 0;
+        while let Some(result) = probes.join_next().await {
+            if let Ok(true) = result {
+                probes_timed_out += 1;
             }
-            // VM: No signal case  -> mark stop
-            if vm_vec[vm_id].status == 3 || vm_vec[vm_id].status == 4 {
-                if vm_vec[vm_id].lost_signal_count > 0 {
-                    vm_vec[vm_id].lost_signal_count -= 1;
-                } else {
-                    mark_vm_stop(vm_vec, vm_id);
-                    println!("vm_id: {} has no signal", vm_id);  
-                }
+        }
+
+        let mut monitor_stats = monitor_stats.lock().unwrap();
+        *monitor_stats = MonitorStats {
+            last_run_at: unix_now(),
+            last_run_duration_ms: started.elapsed().as_millis() as u64,
+            vms_checked,
+            probes_timed_out,
+        };
+    }
+}
+
+// Wait until the guest reports ready, the boot operation only finishes early when the VM failed or exited
+pub async fn wait_vm_ready(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, operations: &OperationList, 
+                            operation_id: &str, vm_id: i16, deadline: Duration) -> Result<u64, (u16, String)> {
+    let started = Instant::now();
+    loop {
+        if let Some(ready_at) = vm_vec.lock().unwrap()[vm_id as usize].ready_at {
+            return Ok(ready_at);
+        }
+        // The operation ends once the VMM is up, the VM itself may still exit before it is ready
+        if let Some(operation) = find_operation(operation_id, operations) {
+            if operation.state == OperationState::Failed {
+                return Err((500, format!("The VM did not become ready: {}", operation.errors.join(", "))));
+            }
+            if operation.state == OperationState::Succeeded && vm_vec.lock().unwrap()[vm_id as usize].status <= 0 {
+                return Err((500, "The VM did not become ready: the VM exited".to_string()));
             }
         }
+        if started.elapsed() >= deadline {
+            return Err((504, format!("The VM was not ready after {} seconds", deadline.as_secs())));
+        }
+        sleep(READY_POLL_INTERVAL).await;
     }
 }
@@ -0,0 +1,252 @@
+use std::{
+    sync::{Arc, Mutex},
+    path::Path,
+    fs,
+};
+use serde_json::json;
+use uuid::Uuid;
+use tracing::{error, info};
+
+use crate::main_lib::structure::{MAXVM, VmStatus, MdevType, MdevParent, MdevInfo, MdevSpec, RequestMdevData};
+use crate::main_lib::vfio::{sysfs_root, pci_device_path, normalize_bdf, write_sysfs};
+use crate::main_lib::init_vm::{load_vm_spec, save_vm_spec, lock_vm_spec, list_vm_specs, write_vm_config};
+use crate::main_lib::manage_vm::{call_vmm_api, wait_device_removed, DEVICE_REMOVE_TIMEOUT};
+use crate::main_lib::manage_storage::is_vm_running;
+
+// Serializes attach and detach across VMs, two VMs could otherwise claim the same mdev
+static MDEV_LOCK: Mutex<()> = Mutex::new(());
+
+fn mdev_types_path(parent: &str) -> String {
+    format!("{}/mdev_supported_types", pci_device_path(parent))
+}
+
+fn mdev_device_path(uuid: &str) -> String {
+    format!("{}/bus/mdev/devices/{}", sysfs_root(), uuid)
+}
+
+fn read_attr(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn list_dir(path: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .map(|entries| entries.flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect())
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+pub fn mdev_cli_arg(mdev: &MdevSpec) -> String {
+    format!("path=/sys/bus/mdev/devices/{}/,id={}", mdev.uuid, mdev.id)
+}
+
+fn get_mdev_type(parent: &str, type_id: &str) -> Option<MdevType> {
+    let path = format!("{}/{}", mdev_types_path(parent), type_id);
+    if !Path::new(&path).exists() {
+        return None;
+    }
+
+    Some(MdevType {
+        id: type_id.to_string(),
+        name: read_attr(&format!("{}/name", path)),
+        description: read_attr(&format!("{}/description", path)),
+        device_api: read_attr(&format!("{}/device_api", path)),
+        available_instances: read_attr(&format!("{}/available_instances", path))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0),
+    })
+}
+
+pub fn list_mdev_types() -> Vec<MdevParent> {
+    list_dir(&format!("{}/bus/pci/devices", sysfs_root())).into_iter()
+        .filter(|bdf| Path::new(&mdev_types_path(bdf)).exists())
+        .map(|bdf| MdevParent {
+            types: list_dir(&mdev_types_path(&bdf)).iter()
+                .filter_map(|type_id| get_mdev_type(&bdf, type_id))
+                .collect(),
+            address: bdf,
+        })
+        .collect()
+}
+
+fn find_mdev_owner(uuid: &str) -> Option<i16> {
+    list_vm_specs().into_iter()
+        .find(|spec| spec.mdevs.iter().any(|mdev| mdev.uuid == uuid))
+        .map(|spec| spec.vm_id)
+}
+
+// The mdev lives under its parent device and mdev_type links to its type directory
+fn get_mdev_info(uuid: &str) -> MdevInfo {
+    let mdev_type = fs::read_link(format!("{}/mdev_type", mdev_device_path(uuid))).ok()
+        .and_then(|link| link.file_name().map(|name| name.to_string_lossy().to_string()));
+    let parent = fs::canonicalize(mdev_device_path(uuid)).ok()
+        .and_then(|path| path.parent()?.file_name().map(|name| name.to_string_lossy().to_string()));
+
+    MdevInfo {
+        uuid: uuid.to_string(),
+        parent,
+        mdev_type,
+        assigned_to: find_mdev_owner(uuid),
+    }
+}
+
+pub fn list_mdevs() -> Vec<MdevInfo> {
+    list_dir(&format!("{}/bus/mdev/devices", sysfs_root())).iter()
+        .map(|uuid| get_mdev_info(uuid))
+        .collect()
+}
+
+pub fn create_mdev(parent: &str, type_id: &str, uuid: Option<&str>) -> Result<MdevInfo, String> {
+    let parent = normalize_bdf(parent)?;
+    let uuid = match uuid {
+        Some(uuid) => Uuid::parse_str(uuid)
+            .map_err(|_| format!("'{}' is not a valid UUID", uuid))?
+            .to_string(),
+        None => Uuid::new_v4().to_string(),
+    };
+    if Path::new(&mdev_device_path(&uuid)).exists() {
+        return Err(format!("The mdev {} already exists", uuid));
+    }
+
+    let mdev_type = get_mdev_type(&parent, type_id)
+        .ok_or(format!("The device {} does not support the mdev type {}", parent, type_id))?;
+    if mdev_type.available_instances == 0 {
+        return Err(format!("No {} instance is available on {}", type_id, parent));
+    }
+
+    info!("Creating the mdev {} of type {} on {}", uuid, type_id, parent);
+    write_sysfs(&format!("{}/{}/create", mdev_types_path(&parent), type_id), &uuid)?;
+    Ok(MdevInfo {
+        uuid,
+        parent: Some(parent),
+        mdev_type: Some(type_id.to_string()),
+        assigned_to: None,
+    })
+}
+
+fn destroy_mdev(uuid: &str) -> Result<(), String> {
+    let path = mdev_device_path(uuid);
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    info!("Removing the mdev {}", uuid);
+    write_sysfs(&format!("{}/remove", path), "1")
+}
+
+pub fn remove_mdev(uuid: &str) -> Result<(), String> {
+    if !Path::new(&mdev_device_path(uuid)).exists() {
+        return Err(format!("The mdev {} does not exist", uuid));
+    }
+    if let Some(vm_id) = find_mdev_owner(uuid) {
+        return Err(format!("The mdev {} is attached to vm_id: {}", uuid, vm_id));
+    }
+    destroy_mdev(uuid)
+}
+
+// Create or reuse an mdev and make it part of the VM, hot adding it when the VM runs
+pub fn attach_mdev(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16, request: &RequestMdevData) 
+                    -> Result<MdevSpec, String> {
+    if vm_id < 0 || vm_id as usize >= MAXVM {
+        return Err(format!("vm id {} is out of range", vm_id));
+    }
+    let _guard = MDEV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _spec_guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut spec = load_vm_spec(&config_path)
+        .map_err(|e| format!("Cannot load the VM spec: {}", e))?;
+
+    let existing = request.uuid.as_deref()
+        .filter(|uuid| Path::new(&mdev_device_path(uuid)).exists());
+    let info = match existing {
+        Some(uuid) => {
+            if let Some(owner) = find_mdev_owner(uuid) {
+                return Err(format!("The mdev {} is attached to vm_id: {}", uuid, owner));
+            }
+            get_mdev_info(uuid)
+        }
+        None => {
+            let parent = request.parent.as_deref().ok_or("parent is required to create an mdev")?;
+            let mdev_type = request.mdev_type.as_deref().ok_or("mdev_type is required to create an mdev")?;
+            create_mdev(parent, mdev_type, request.uuid.as_deref())?
+        }
+    };
+
+    let index = (0..).find(|index| !spec.mdevs.iter().any(|mdev| mdev.id == format!("mdev{}", index)))
+        .unwrap_or(0);
+    let mdev = MdevSpec {
+        id: format!("mdev{}", index),
+        uuid: info.uuid.clone(),
+        parent: info.parent.unwrap_or_default(),
+        mdev_type: info.mdev_type.unwrap_or_default(),
+    };
+
+    if is_vm_running(vm_vec, vm_id) {
+        let body = json!({
+            "path": format!("/sys/bus/mdev/devices/{}/", mdev.uuid),
+            "id": mdev.id
+        });
+        if let Err(e) = call_vmm_api(vm_id, "PUT", "vm.add-device", Some(&body.to_string())) {
+            if existing.is_none() {
+                let _ = destroy_mdev(&mdev.uuid);
+            }
+            return Err(e);
+        }
+    }
+
+    spec.mdevs.push(mdev.clone());
+    save_vm_spec(&config_path, &spec).map_err(|e| format!("Cannot save the VM spec: {}", e))?;
+    write_vm_config(&config_path, &spec).map_err(|e| format!("Cannot write the VM config: {}", e))?;
+    Ok(mdev)
+}
+
+pub fn detach_mdev(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: i16, uuid: &str) -> Result<(), String> {
+    if vm_id < 0 || vm_id as usize >= MAXVM {
+        return Err(format!("vm id {} is out of range", vm_id));
+    }
+    let _guard = MDEV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _spec_guard = lock_vm_spec(vm_id);
+    let config_path = format!("../vms-config/{}", vm_id);
+    let mut spec = load_vm_spec(&config_path)
+        .map_err(|e| format!("Cannot load the VM spec: {}", e))?;
+    let mdev = spec.mdevs.iter().find(|mdev| mdev.uuid == uuid).cloned()
+        .ok_or(format!("The mdev {} is not attached to vm_id: {}", uuid, vm_id))?;
+
+    if is_vm_running(vm_vec, vm_id) {
+        let body = json!({"id": mdev.id});
+        call_vmm_api(vm_id, "PUT", "vm.remove-device", Some(&body.to_string()))?;
+        // The mdev cannot be destroyed while the guest still holds it
+        if !wait_device_removed(vm_id, &mdev.id, DEVICE_REMOVE_TIMEOUT) {
+            return Err(format!("The guest did not release the mdev {}, it stays attached", uuid));
+        }
+    }
+
+    spec.mdevs.retain(|mdev| mdev.uuid != uuid);
+    save_vm_spec(&config_path, &spec).map_err(|e| format!("Cannot save the VM spec: {}", e))?;
+    write_vm_config(&config_path, &spec).map_err(|e| format!("Cannot write the VM config: {}", e))?;
+    destroy_mdev(uuid)
+}
+
+// mdevs do not survive a host reboot, create the missing ones before launch
+pub fn prepare_vm_mdevs(mdevs: &[MdevSpec]) -> Result<(), String> {
+    for mdev in mdevs {
+        if !Path::new(&mdev_device_path(&mdev.uuid)).exists() {
+            create_mdev(&mdev.parent, &mdev.mdev_type, Some(&mdev.uuid))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn destroy_vm_mdevs(vm_id: i16) {
+    let config_path = format!("../vms-config/{}", vm_id);
+    if let Ok(spec) = load_vm_spec(&config_path) {
+        for mdev in spec.mdevs.iter() {
+            if let Err(e) = destroy_mdev(&mdev.uuid) {
+                error!("Cannot remove the mdev {}: {}", mdev.uuid, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    sync::{Arc, Mutex},
+    collections::BTreeMap,
+    fmt::Write,
+    thread,
+    time::{Duration, Instant},
+};
+use serde_json::Value;
+use sysinfo::{Disks, Pid, ProcessesToUpdate, System};
+
+use crate::main_lib::structure::{STATUS, MAXVM, VmStatus, MonitorStats};
+use crate::main_lib::manage_vm::call_vmm_api_with_timeout;
+
+// Upper bounds in seconds of the API latency histogram
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// Bounds of the vm.counters calls of a scrape, the VMs left after the deadline are skipped
+const COUNTERS_TIMEOUT: Duration = Duration::from_secs(2);
+const COUNTERS_PARALLELISM: usize = 16;
+const COUNTERS_DEADLINE: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct RequestStats {
+    pub count: u64,
+    pub sum: f64,
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+// The System is kept between scrapes so the CPU usage covers the scrape interval
+pub struct Metrics {
+    pub system: System,
+    pub requests: BTreeMap<(String, String, u16), RequestStats>,
+}
+
+pub type MetricsState = Arc<Mutex<Metrics>>;
+
+pub fn new_metrics() -> MetricsState {
+    Arc::new(Mutex::new(Metrics {
+        system: System::new(),
+        requests: BTreeMap::new(),
+    }))
+}
+
+pub fn record_request(metrics: &MetricsState, method: &str, route: &str, status: u16, seconds: f64) {
+    let mut metrics = metrics.lock().unwrap();
+    let stats = metrics.requests
+        .entry((method.to_string(), route.to_string(), status))
+        .or_default();
+    stats.count += 1;
+    stats.sum += seconds;
+    for (index, bound) in LATENCY_BUCKETS.iter().enumerate() {
+        if seconds <= *bound {
+            stats.buckets[index] += 1;
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&str, String)], value: f64) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+        return;
+    }
+    let labels: Vec<String> = labels.iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+        .collect();
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+}
+
+fn render_host(out: &mut String, system: &mut System) {
+    system.refresh_cpu_usage();
+    system.refresh_memory();
+
+    write_header(out, "chv_host_cpu_usage_ratio", "gauge", "Host CPU usage over the last scrape interval.");
+    write_sample(out, "chv_host_cpu_usage_ratio", &[], (system.global_cpu_usage() / 100.0).into());
+    write_header(out, "chv_host_cpus", "gauge", "Logical CPUs of the host.");
+    write_sample(out, "chv_host_cpus", &[], system.cpus().len() as f64);
+
+    let load = System::load_average();
+    write_header(out, "chv_host_load_average", "gauge", "Host load average.");
+    write_sample(out, "chv_host_load_average", &[("period", "1m".to_string())], load.one);
+    write_sample(out, "chv_host_load_average", &[("period", "5m".to_string())], load.five);
+    write_sample(out, "chv_host_load_average", &[("period", "15m".to_string())], load.fifteen);
+
+    write_header(out, "chv_host_memory_bytes", "gauge", "Host memory in bytes.");
+    write_sample(out, "chv_host_memory_bytes", &[("kind", "total".to_string())], system.total_memory() as f64);
+    write_sample(out, "chv_host_memory_bytes", &[("kind", "used".to_string())], system.used_memory() as f64);
+    write_sample(out, "chv_host_memory_bytes", &[("kind", "available".to_string())], 
+                system.available_memory() as f64);
+
+    let disks = Disks::new_with_refreshed_list();
+    write_header(out, "chv_host_disk_bytes", "gauge", "Host filesystem space in bytes.");
+    for disk in disks.list() {
+        let mount_point = disk.mount_point().to_string_lossy().to_string();
+        write_sample(out, "chv_host_disk_bytes", 
+                    &[("mount_point", mount_point.clone()), ("kind", "total".to_string())], 
+                    disk.total_space() as f64);
+        write_sample(out, "chv_host_disk_bytes", 
+                    &[("mount_point", mount_point), ("kind", "available".to_string())], 
+                    disk.available_space() as f64);
+    }
+}
+
+fn render_requests(out: &mut String, requests: &BTreeMap<(String, String, u16), RequestStats>) {
+    write_header(out, "chv_api_request_duration_seconds", "histogram", "API request latencies.");
+    for ((method, route, status), stats) in requests.iter() {
+        let labels = [("method", method.clone()), ("route", route.clone()), ("status", status.to_string())];
+        for (index, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", bound.to_string()));
+            write_sample(out, "chv_api_request_duration_seconds_bucket", &bucket_labels, 
+                        stats.buckets[index] as f64);
+        }
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", "+Inf".to_string()));
+        write_sample(out, "chv_api_request_duration_seconds_bucket", &bucket_labels, stats.count as f64);
+        write_sample(out, "chv_api_request_duration_seconds_sum", &labels, stats.sum);
+        write_sample(out, "chv_api_request_duration_seconds_count", &labels, stats.count as f64);
+    }
+}
+
+fn render_monitor(out: &mut String, monitor_stats: &MonitorStats) {
+    write_header(out, "chv_monitor_last_run_timestamp_seconds", "gauge", "End of the last monitor run.");
+    write_sample(out, "chv_monitor_last_run_timestamp_seconds", &[], monitor_stats.last_run_at as f64);
+    write_header(out, "chv_monitor_last_run_duration_seconds", "gauge", "Duration of the last monitor run.");
+    write_sample(out, "chv_monitor_last_run_duration_seconds", &[], 
+                monitor_stats.last_run_duration_ms as f64 / 1000.0);
+    write_header(out, "chv_monitor_vms_checked", "gauge", "VMs checked by the last monitor run.");
+    write_sample(out, "chv_monitor_vms_checked", &[], monitor_stats.vms_checked as f64);
+    write_header(out, "chv_monitor_probes_timed_out", "gauge", "Probes over the deadline in the last monitor run.");
+    write_sample(out, "chv_monitor_probes_timed_out", &[], monitor_stats.probes_timed_out as f64);
+}
+
+struct VmSample {
+    vm_id: usize,
+    status: i32,
+    pid: Option<Pid>,
+    probe_latency_ms: u64,
+}
+
+fn render_vms(out: &mut String, system: &mut System, vms: &[VmSample]) {
+    write_header(out, "chv_vms", "gauge", "VMs by state.");
+    for (state, name) in STATUS.iter().enumerate() {
+        let count = vms.iter().filter(|vm| vm.status == state as i32).count();
+        write_sample(out, "chv_vms", &[("state", name.to_string())], count as f64);
+    }
+
+    let pids: Vec<Pid> = vms.iter().filter_map(|vm| vm.pid).collect();
+    system.refresh_processes(ProcessesToUpdate::Some(&pids), true);
+
+    write_header(out, "chv_vm_process_cpu_usage_ratio", "gauge", 
+                "CPU usage of the VMM process, 1.0 is one host CPU.");
+    for vm in vms.iter() {
+        if let Some(process) = vm.pid.and_then(|pid| system.process(pid)) {
+            write_sample(out, "chv_vm_process_cpu_usage_ratio", &[("vm_id", vm.vm_id.to_string())], 
+                        (process.cpu_usage() / 100.0).into());
+        }
+    }
+    write_header(out, "chv_vm_process_resident_memory_bytes", "gauge", "Resident memory of the VMM process.");
+    for vm in vms.iter() {
+        if let Some(process) = vm.pid.and_then(|pid| system.process(pid)) {
+            write_sample(out, "chv_vm_process_resident_memory_bytes", &[("vm_id", vm.vm_id.to_string())], 
+                        process.memory() as f64);
+        }
+    }
+    write_header(out, "chv_vm_probe_latency_seconds", "gauge", "Latency of the last health check.");
+    for vm in vms.iter().filter(|vm| vm.status > 0) {
+        write_sample(out, "chv_vm_probe_latency_seconds", &[("vm_id", vm.vm_id.to_string())], 
+                    vm.probe_latency_ms as f64 / 1000.0);
+    }
+
+    // vm.counters groups the counters by device, such as _disk0 or _net1
+    write_header(out, "chv_vm_device_counter_total", "counter", "Device counters reported by vm.counters.");
+    let vm_ids: Vec<usize> = vms.iter()
+        .filter(|vm| vm.status == 2 || vm.status == 5)
+        .map(|vm| vm.vm_id)
+        .collect();
+    for (vm_id, counters) in collect_counters(&vm_ids) {
+        let devices = match counters.as_object() {
+            Some(devices) => devices,
+            None => continue,
+        };
+        for (device, values) in devices.iter() {
+            for (counter, value) in values.as_object().into_iter().flatten() {
+                if let Some(value) = value.as_f64() {
+                    write_sample(out, "chv_vm_device_counter_total", &[("vm_id", vm_id.to_string()), 
+                                ("device", device.clone()), ("counter", counter.clone())], value);
+                }
+            }
+        }
+    }
+}
+
+// Query vm.counters on a bounded number of threads, like the monitor probes
+fn collect_counters(vm_ids: &[usize]) -> Vec<(usize, Value)> {
+    let started = Instant::now();
+    let queue = Mutex::new(vm_ids.iter().copied());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..COUNTERS_PARALLELISM.min(vm_ids.len()) {
+            scope.spawn(|| loop {
+                let Some(vm_id) = queue.lock().unwrap().next() else { break };
+                if started.elapsed() >= COUNTERS_DEADLINE {
+                    break;
+                }
+                let counters = call_vmm_api_with_timeout(vm_id as i16, "GET", "vm.counters", None, 
+                                                        COUNTERS_TIMEOUT).ok()
+                    .and_then(|counters| serde_json::from_str::<Value>(&counters).ok());
+                if let Some(counters) = counters {
+                    results.lock().unwrap().push((vm_id, counters));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(vm_id, _)| *vm_id);
+    results
+}
+
+pub fn render_metrics(metrics: &MetricsState, vm_vec: &Arc<Mutex<Vec<VmStatus>>>, 
+                        monitor_stats: &Arc<Mutex<MonitorStats>>) -> String {
+    let vms: Vec<VmSample> = {
+        let vm_vec = vm_vec.lock().unwrap();
+        (0..MAXVM)
+            .filter(|vm_id| vm_vec[*vm_id].status >= 0)
+            .map(|vm_id| VmSample {
+                vm_id,
+                status: vm_vec[vm_id].status,
+                pid: vm_vec[vm_id].process_id.parse::<usize>().ok().map(Pid::from),
+                probe_latency_ms: vm_vec[vm_id].health.probe_latency_ms,
+            })
+            .collect()
+    };
+    let monitor_stats = monitor_stats.lock().unwrap().clone();
+
+    // The System is taken out so the lock is not held during the vm.counters calls
+    let mut system = {
+        let mut metrics = metrics.lock().unwrap();
+        std::mem::replace(&mut metrics.system, System::new())
+    };
+
+    let mut out = String::new();
+    render_host(&mut out, &mut system);
+    render_vms(&mut out, &mut system, &vms);
+    render_monitor(&mut out, &monitor_stats);
+
+    let mut metrics = metrics.lock().unwrap();
+    metrics.system = system;
+    render_requests(&mut out, &metrics.requests);
+    out
+}
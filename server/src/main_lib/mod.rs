@@ -1,4 +1,17 @@
 pub mod structure;
 pub mod init_vm;
 pub mod manage_vm;
-pub mod manage_pci;
\ No newline at end of file
+pub mod manage_pci;
+pub mod cloud_init;
+pub mod manage_storage;
+pub mod vfio;
+pub mod operations;
+pub mod manage_gpu;
+pub mod sriov;
+pub mod mdev;
+pub mod health;
+pub mod metrics;
+pub mod vm_stats;
+pub mod host;
+pub mod logging;
+pub mod console;
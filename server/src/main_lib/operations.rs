@@ -0,0 +1,103 @@
+use std::{
+    sync::{Arc, Mutex},
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+// Finished operations are kept for an hour
+const OPERATION_TTL: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Operation {
+    pub id: String,
+    pub kind: String,
+    pub vm_id: i16,
+    pub state: OperationState,
+    pub results: Vec<Value>,
+    pub errors: Vec<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+pub type OperationList = Arc<Mutex<HashMap<String, Operation>>>;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+fn update_operation(operation_id: &str, operations: &OperationList, update: impl FnOnce(&mut Operation)) {
+    let mut operations = operations.lock().unwrap();
+    if let Some(operation) = operations.get_mut(operation_id) {
+        update(operation);
+        operation.updated_at = now();
+    }
+}
+
+// Drop the finished operations older than the TTL
+pub fn gc_operations(operations: &OperationList) {
+    let expire = now().saturating_sub(OPERATION_TTL);
+    let mut operations = operations.lock().unwrap();
+    operations.retain(|_, operation| {
+        matches!(operation.state, OperationState::Pending | OperationState::Running)
+            || operation.updated_at > expire
+    });
+}
+
+pub fn create_operation(kind: &str, vm_id: i16, operations: &OperationList) -> String {
+    gc_operations(operations);
+
+    let operation = Operation {
+        id: Uuid::new_v4().to_string(),
+        kind: kind.to_string(),
+        vm_id,
+        state: OperationState::Pending,
+        results: Vec::new(),
+        errors: Vec::new(),
+        created_at: now(),
+        updated_at: now(),
+    };
+    let operation_id = operation.id.clone();
+    operations.lock().unwrap().insert(operation_id.clone(), operation);
+    operation_id
+}
+
+pub fn start_operation(operation_id: &str, operations: &OperationList) {
+    update_operation(operation_id, operations, |operation| {
+        operation.state = OperationState::Running;
+    });
+}
+
+pub fn push_operation_result(operation_id: &str, result: Value, operations: &OperationList) {
+    update_operation(operation_id, operations, |operation| operation.results.push(result));
+}
+
+pub fn push_operation_error(operation_id: &str, error: &str, operations: &OperationList) {
+    update_operation(operation_id, operations, |operation| operation.errors.push(error.to_string()));
+}
+
+// An operation fails as soon as one of its items reported an error
+pub fn finish_operation(operation_id: &str, operations: &OperationList) {
+    update_operation(operation_id, operations, |operation| {
+        operation.state = if operation.errors.is_empty() {
+            OperationState::Succeeded
+        } else {
+            OperationState::Failed
+        };
+    });
+}
+
+pub fn find_operation(operation_id: &str, operations: &OperationList) -> Option<Operation> {
+    operations.lock().unwrap().get(operation_id).cloned()
+}
@@ -0,0 +1,392 @@
+use std::{
+    sync::{Arc, Mutex},
+    path::Path,
+    fs,
+};
+use tracing::{error, info};
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_REQUEST};
+use netlink_packet_route::{LinkMessage, RtnlMessage, link::nlas::Nla};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+
+use crate::main_lib::structure::{PciLedger, SriovInfo, VfInfo, HostVf, allocate_pci, release_pci};
+use crate::main_lib::vfio::{sysfs_root, pci_device_path, normalize_bdf, write_sysfs, get_pci_driver, 
+                            get_iommu_group};
+
+fn read_sysfs_number(path: &str) -> Option<u32> {
+    fs::read_to_string(path).ok().and_then(|value| value.trim().parse().ok())
+}
+
+fn link_name(path: &str) -> Option<String> {
+    fs::read_link(path).ok()
+        .and_then(|link| link.file_name().map(|name| name.to_string_lossy().to_string()))
+}
+
+pub fn is_sriov_pf(bdf: &str) -> bool {
+    Path::new(&format!("{}/sriov_totalvfs", pci_device_path(bdf))).exists()
+}
+
+// First network interface of the device, VF settings go through the PF interface
+fn get_netdev(bdf: &str) -> Option<String> {
+    let mut netdevs: Vec<String> = fs::read_dir(format!("{}/net", pci_device_path(bdf))).ok()?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    netdevs.sort();
+    netdevs.into_iter().next()
+}
+
+fn get_mac(bdf: &str) -> Option<String> {
+    let netdev = get_netdev(bdf)?;
+    fs::read_to_string(format!("{}/net/{}/address", pci_device_path(bdf), netdev)).ok()
+        .map(|mac| mac.trim().to_string())
+}
+
+// The virtfnN links of a PF point at its VFs
+fn list_virtfns(pf: &str) -> Vec<(u32, String)> {
+    let mut vfs: Vec<(u32, String)> = fs::read_dir(pci_device_path(pf))
+        .map(|entries| entries.flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let index = name.strip_prefix("virtfn")?.parse().ok()?;
+                let bdf = link_name(&entry.path().to_string_lossy())?;
+                Some((index, bdf))
+            })
+            .collect())
+        .unwrap_or_default();
+    vfs.sort();
+    vfs
+}
+
+pub fn get_sriov_info(pf: &str, ledger: &PciLedger) -> Option<SriovInfo> {
+    if !is_sriov_pf(pf) {
+        return None;
+    }
+
+    let path = pci_device_path(pf);
+    let vfs = list_virtfns(pf).into_iter()
+        .map(|(index, bdf)| VfInfo {
+            parent: pf.to_string(),
+            index,
+            driver: get_pci_driver(&bdf),
+            mac: get_mac(&bdf),
+            iommu_group: get_iommu_group(&bdf),
+            assigned_to: ledger.get(&bdf).cloned(),
+            address: bdf,
+        })
+        .collect();
+
+    Some(SriovInfo {
+        address: pf.to_string(),
+        netdev: get_netdev(pf),
+        driver: get_pci_driver(pf),
+        total_vfs: read_sysfs_number(&format!("{}/sriov_totalvfs", path)).unwrap_or(0),
+        num_vfs: read_sysfs_number(&format!("{}/sriov_numvfs", path)).unwrap_or(0),
+        vfs,
+    })
+}
+
+pub fn list_sriov_devices(pci_ledger: &Arc<Mutex<PciLedger>>) -> Vec<SriovInfo> {
+    let ledger = pci_ledger.lock().unwrap().clone();
+    let mut addresses: Vec<String> = fs::read_dir(format!("{}/bus/pci/devices", sysfs_root()))
+        .map(|entries| entries.flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect())
+        .unwrap_or_default();
+    addresses.sort();
+
+    addresses.iter()
+        .filter_map(|bdf| get_sriov_info(bdf, &ledger))
+        .collect()
+}
+
+// The kernel only accepts a new VF count when the current one is zero
+pub fn set_num_vfs(address: &str, num_vfs: u32, pci_ledger: &Arc<Mutex<PciLedger>>) 
+                    -> Result<SriovInfo, String> {
+    let pf = normalize_bdf(address)?;
+    let ledger = pci_ledger.lock().unwrap().clone();
+    let info = get_sriov_info(&pf, &ledger)
+        .ok_or(format!("The device {} is not an SR-IOV physical function", pf))?;
+
+    if num_vfs > info.total_vfs {
+        return Err(format!("The device {} supports at most {} VFs", pf, info.total_vfs));
+    }
+    if num_vfs == info.num_vfs {
+        return Ok(info);
+    }
+    if let Some(vf) = info.vfs.iter().find(|vf| vf.assigned_to.is_some()) {
+        return Err(format!("The VF {} of {} is assigned to vm_id: {}", 
+                            vf.address, pf, vf.assigned_to.as_ref().unwrap().vm_id));
+    }
+
+    let numvfs_path = format!("{}/sriov_numvfs", pci_device_path(&pf));
+    info!("Setting the VFs of {} from {} to {}", pf, info.num_vfs, num_vfs);
+    if info.num_vfs != 0 {
+        write_sysfs(&numvfs_path, "0")?;
+    }
+    if num_vfs != 0 {
+        write_sysfs(&numvfs_path, &num_vfs.to_string())?;
+    }
+
+    get_sriov_info(&pf, &ledger).ok_or(format!("Cannot read the SR-IOV state of {}", pf))
+}
+
+fn is_valid_mac(mac: &str) -> bool {
+    let octets: Vec<&str> = mac.split(':').collect();
+    octets.len() == 6 && octets.iter().all(|octet| {
+        octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+// Nested attributes of IFLA_VFINFO_LIST, netlink-packet-route only carries the raw payload
+const IFLA_VF_INFO: u16 = 1;
+const IFLA_VF_MAC: u16 = 1;
+const IFLA_VF_VLAN: u16 = 2;
+const NETLINK_ACK_LEN: usize = 4096;
+
+// One netlink attribute, the payload is padded to 4 bytes
+fn encode_nla(kind: u16, payload: &[u8]) -> Vec<u8> {
+    let len = 4 + payload.len();
+    let mut nla = Vec::with_capacity((len + 3) & !3);
+    nla.extend_from_slice(&(len as u16).to_ne_bytes());
+    nla.extend_from_slice(&kind.to_ne_bytes());
+    nla.extend_from_slice(payload);
+    nla.resize((len + 3) & !3, 0);
+    nla
+}
+
+fn parse_mac(mac: &str) -> Result<[u8; 6], String> {
+    if !is_valid_mac(mac) {
+        return Err(format!("'{}' is not a valid MAC address", mac));
+    }
+    let mut bytes = [0u8; 6];
+    for (byte, part) in bytes.iter_mut().zip(mac.split(':')) {
+        *byte = u8::from_str_radix(part, 16).map_err(|_| format!("'{}' is not a valid MAC address", mac))?;
+    }
+    Ok(bytes)
+}
+
+// The IFLA_VF_INFO entry of a VF, struct ifla_vf_mac and struct ifla_vf_vlan of the kernel
+fn vf_info_nla(index: u32, mac: Option<&str>, vlan: Option<u16>) -> Result<Option<Vec<u8>>, String> {
+    let mut info = Vec::new();
+    if let Some(mac) = mac {
+        let mut payload = index.to_ne_bytes().to_vec();
+        payload.extend_from_slice(&parse_mac(mac)?);
+        payload.resize(4 + 32, 0);
+        info.extend(encode_nla(IFLA_VF_MAC, &payload));
+    }
+    if let Some(vlan) = vlan {
+        if vlan > 4094 {
+            return Err(format!("'{}' is not a valid VLAN id", vlan));
+        }
+        let mut payload = index.to_ne_bytes().to_vec();
+        payload.extend_from_slice(&(vlan as u32).to_ne_bytes());
+        payload.extend_from_slice(&0u32.to_ne_bytes());
+        info.extend(encode_nla(IFLA_VF_VLAN, &payload));
+    }
+    if info.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(encode_nla(IFLA_VF_INFO, &info)))
+}
+
+// RTM_SETLINK on the PF interface, the kernel answers with an ACK or the errno
+fn set_link_vf_info(ifindex: u32, vf_info: Vec<u8>) -> Result<(), String> {
+    let mut link = LinkMessage::default();
+    link.header.index = ifindex;
+    link.nlas.push(Nla::VfInfoList(vf_info));
+    let mut message = NetlinkMessage::from(RtnlMessage::SetLink(link));
+    message.header.flags = NLM_F_REQUEST | NLM_F_ACK;
+    message.header.sequence_number = 1;
+    message.finalize();
+    let mut request = vec![0; message.buffer_len()];
+    message.serialize(&mut request);
+
+    let mut socket = Socket::new(NETLINK_ROUTE).map_err(|e| format!("Cannot open a netlink socket: {}", e))?;
+    socket.bind_auto().map_err(|e| format!("Cannot bind the netlink socket: {}", e))?;
+    socket.connect(&SocketAddr::new(0, 0)).map_err(|e| format!("Cannot connect the netlink socket: {}", e))?;
+    socket.send(&request, 0).map_err(|e| format!("Cannot send the netlink request: {}", e))?;
+
+    let mut response = vec![0; NETLINK_ACK_LEN];
+    let len = socket.recv(&mut &mut response[..], 0)
+        .map_err(|e| format!("Cannot read the netlink answer: {}", e))?;
+    let answer = NetlinkMessage::<RtnlMessage>::deserialize(&response[..len])
+        .map_err(|e| format!("Cannot decode the netlink answer: {}", e))?;
+    match answer.payload {
+        NetlinkPayload::Error(error) if error.code.is_some() => Err(error.to_io().to_string()),
+        NetlinkPayload::Error(_) => Ok(()),
+        _ => Err("Unexpected netlink answer".to_string()),
+    }
+}
+
+// Program the VF MAC and VLAN through the PF netdev with an RTM_SETLINK request
+pub fn set_vf_config(address: &str, index: u32, mac: Option<&str>, vlan: Option<u16>) 
+                    -> Result<(), String> {
+    let pf = normalize_bdf(address)?;
+    if !list_virtfns(&pf).iter().any(|(vf_index, _)| *vf_index == index) {
+        return Err(format!("The device {} has no VF {}", pf, index));
+    }
+    let netdev = get_netdev(&pf).ok_or(format!("The device {} has no network interface", pf))?;
+    let vf_info = match vf_info_nla(index, mac, vlan)? {
+        Some(vf_info) => vf_info,
+        None => return Ok(()),
+    };
+    let ifindex = read_sysfs_number(&format!("{}/net/{}/ifindex", pci_device_path(&pf), netdev))
+        .ok_or(format!("Cannot read the interface index of {}", netdev))?;
+
+    match set_link_vf_info(ifindex, vf_info) {
+        Ok(_) => {
+            info!("Set the VF {} of {} successfully.", index, pf);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Cannot set the VF {} of {}: {}", index, pf, e);
+            Err(format!("Cannot set the VF {} of {}: {}", index, pf, e))
+        }
+    }
+}
+
+fn reserve_vf(vm_id: i16, hostvf: &HostVf, reserved: &mut Vec<String>, allocated: &mut Vec<String>,
+                pci_ledger: &Arc<Mutex<PciLedger>>) -> Result<(), String> {
+    let pf = normalize_bdf(&hostvf.parent)?;
+    if !is_sriov_pf(&pf) {
+        return Err(format!("The device {} is not an SR-IOV physical function", pf));
+    }
+
+    let free = {
+        let ledger = pci_ledger.lock().unwrap();
+        list_virtfns(&pf).into_iter()
+            .find(|(_, bdf)| !ledger.contains_key(bdf) && !reserved.contains(bdf))
+    };
+    let (index, bdf) = free.ok_or(format!("The device {} has no free VF", pf))?;
+    if allocate_pci(&bdf, vm_id, pci_ledger)? {
+        allocated.push(bdf.clone());
+    }
+    reserved.push(bdf);
+
+    // The MAC has to be set before the guest driver probes the VF
+    set_vf_config(&pf, index, hostvf.mac.as_deref(), hostvf.vlan)
+}
+
+// Reserve a free VF of each requested PF and program it, all of them or none
+pub fn reserve_vfs(vm_id: i16, hostvfs: &[HostVf], pci_ledger: &Arc<Mutex<PciLedger>>) 
+                    -> Result<Vec<String>, String> {
+    let mut reserved: Vec<String> = Vec::new();
+    // Only the entries created here are released on failure
+    let mut allocated: Vec<String> = Vec::new();
+    for hostvf in hostvfs {
+        if let Err(e) = reserve_vf(vm_id, hostvf, &mut reserved, &mut allocated, pci_ledger) {
+            for bdf in allocated.iter() {
+                release_pci(bdf, vm_id, pci_ledger);
+            }
+            return Err(e);
+        }
+    }
+    Ok(reserved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main_lib::structure::PciAllocation;
+    use crate::main_lib::vfio::tests::{add_device, fake_sysfs};
+    use std::os::unix::fs::symlink;
+
+    // A PF with two of its four VFs enabled
+    fn add_pf(root: &Path) {
+        let devices = root.join("bus/pci/devices");
+        add_device(root, "0000:3b:00.0", Some("ice"));
+        add_device(root, "0000:3b:01.0", Some("iavf"));
+        add_device(root, "0000:3b:01.1", None);
+        add_device(root, "0000:5e:00.0", Some("nvme"));
+
+        let pf = devices.join("0000:3b:00.0");
+        fs::write(pf.join("sriov_totalvfs"), "4\n").unwrap();
+        fs::write(pf.join("sriov_numvfs"), "2\n").unwrap();
+        symlink(devices.join("0000:3b:01.0"), pf.join("virtfn0")).unwrap();
+        symlink(devices.join("0000:3b:01.1"), pf.join("virtfn1")).unwrap();
+        fs::create_dir_all(pf.join("net/ens1f0")).unwrap();
+        fs::create_dir_all(devices.join("0000:3b:01.0/net/ens1f0v0")).unwrap();
+        fs::write(devices.join("0000:3b:01.0/net/ens1f0v0/address"), "52:54:00:12:34:56\n").unwrap();
+    }
+
+    fn ledger(entries: &[(&str, i16)]) -> Arc<Mutex<PciLedger>> {
+        Arc::new(Mutex::new(entries.iter()
+            .map(|(bdf, vm_id)| (bdf.to_string(), PciAllocation { vm_id: *vm_id, device_id: String::new() }))
+            .collect()))
+    }
+
+    #[test]
+    fn list_sriov_devices_reads_the_pfs() {
+        let (_guard, root) = fake_sysfs();
+        add_pf(root.path());
+
+        let devices = list_sriov_devices(&ledger(&[("0000:3b:01.1", 5)]));
+        assert_eq!(devices.len(), 1);
+        let pf = &devices[0];
+        assert_eq!(pf.address, "0000:3b:00.0");
+        assert_eq!(pf.netdev.as_deref(), Some("ens1f0"));
+        assert_eq!(pf.driver.as_deref(), Some("ice"));
+        assert_eq!((pf.total_vfs, pf.num_vfs), (4, 2));
+
+        let vfs: Vec<(u32, &str)> = pf.vfs.iter().map(|vf| (vf.index, vf.address.as_str())).collect();
+        assert_eq!(vfs, vec![(0, "0000:3b:01.0"), (1, "0000:3b:01.1")]);
+        assert_eq!(pf.vfs[0].mac.as_deref(), Some("52:54:00:12:34:56"));
+        assert_eq!(pf.vfs[0].driver.as_deref(), Some("iavf"));
+        assert!(pf.vfs[0].assigned_to.is_none());
+        assert_eq!(pf.vfs[1].assigned_to.as_ref().map(|allocation| allocation.vm_id), Some(5));
+    }
+
+    #[test]
+    fn set_num_vfs_writes_the_count() {
+        let (_guard, root) = fake_sysfs();
+        add_pf(root.path());
+        let numvfs = root.path().join("bus/pci/devices/0000:3b:00.0/sriov_numvfs");
+
+        let info = set_num_vfs("3b:00.0", 3, &ledger(&[])).unwrap();
+        assert_eq!(info.num_vfs, 3);
+        assert_eq!(fs::read_to_string(&numvfs).unwrap(), "3");
+
+        // The same count leaves sysfs alone
+        fs::write(&numvfs, "3\n").unwrap();
+        assert_eq!(set_num_vfs("0000:3b:00.0", 3, &ledger(&[])).unwrap().num_vfs, 3);
+        assert_eq!(fs::read_to_string(&numvfs).unwrap(), "3\n");
+    }
+
+    #[test]
+    fn set_num_vfs_rejects_invalid_changes() {
+        let (_guard, root) = fake_sysfs();
+        add_pf(root.path());
+
+        assert!(set_num_vfs("0000:3b:00.0", 5, &ledger(&[])).unwrap_err().contains("at most 4"));
+        assert!(set_num_vfs("0000:3b:00.0", 0, &ledger(&[("0000:3b:01.0", 2)])).unwrap_err()
+                .contains("assigned to vm_id: 2"));
+        assert!(set_num_vfs("0000:5e:00.0", 1, &ledger(&[])).unwrap_err()
+                .contains("not an SR-IOV physical function"));
+
+        let numvfs = root.path().join("bus/pci/devices/0000:3b:00.0/sriov_numvfs");
+        assert_eq!(fs::read_to_string(numvfs).unwrap(), "2\n");
+    }
+
+    #[test]
+    fn vf_info_nla_encodes_mac_and_vlan() {
+        let nla = vf_info_nla(1, Some("52:54:00:AB:CD:EF"), Some(100)).unwrap().unwrap();
+        // IFLA_VF_INFO holding a 40 bytes IFLA_VF_MAC and a 16 bytes IFLA_VF_VLAN
+        assert_eq!(nla.len(), 4 + 40 + 16);
+        assert_eq!(u16::from_ne_bytes([nla[0], nla[1]]), 60);
+        assert_eq!(u16::from_ne_bytes([nla[2], nla[3]]), IFLA_VF_INFO);
+        let mac = &nla[4..44];
+        assert_eq!(u16::from_ne_bytes([mac[2], mac[3]]), IFLA_VF_MAC);
+        assert_eq!(u32::from_ne_bytes(mac[4..8].try_into().unwrap()), 1);
+        assert_eq!(&mac[8..14], &[0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]);
+        let vlan = &nla[44..];
+        assert_eq!(u16::from_ne_bytes([vlan[2], vlan[3]]), IFLA_VF_VLAN);
+        assert_eq!(u32::from_ne_bytes(vlan[8..12].try_into().unwrap()), 100);
+    }
+
+    #[test]
+    fn vf_info_nla_rejects_invalid_settings() {
+        assert_eq!(vf_info_nla(0, None, None), Ok(None));
+        assert!(vf_info_nla(0, Some("52:54:00:ab:cd"), None).is_err());
+        assert!(vf_info_nla(0, None, Some(4095)).is_err());
+    }
+}
@@ -1,7 +1,7 @@
-use std::{sync::{Arc, Mutex, MutexGuard}, collections::LinkedList};
+use std::{sync::{Arc, Mutex, MutexGuard}, collections::{BTreeMap, VecDeque}, fs};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use sha1::{Digest, Sha1};
+use serde_json::{Map, Value};
+use tracing::{error, warn};
 
 pub const STATUS: [&str; 8] = ["Stopped", "Booting", "Running", "Unknown", 
                                 "Stopping", "Paused", "Locked", "Migrating"]; 
@@ -11,32 +11,371 @@ pub const MAXVM: usize = 253;
 pub struct VmStatus {
     pub process_id: Box<str>,
     pub status: i32,
-    pub lost_signal_count: usize
+    pub lost_signal_count: usize,
+    pub health: VmHealth,
+    pub history: VecDeque<StatsSample>,
+    // Set when the guest reports on the serial console that it finished booting
+    pub ready_at: Option<u64>,
+    // Bumped on every launch so the exit of a previous VMM leaves the current one alone
+    pub launch: u64,
+}
+
+// Resource usage of a running VM, the device counters come from vm.counters
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VmStats {
+    pub vm_id: i16,
+    pub pid: String,
+    pub uptime_seconds: f64,
+    pub vcpu_seconds: f64,
+    pub vcpus: BTreeMap<String, f64>,
+    pub host_rss_bytes: u64,
+    pub memory_size: Option<u64>,
+    pub memory_actual_size: Option<u64>,
+    pub balloon_size: Option<u64>,
+    pub disks: BTreeMap<String, Map<String, Value>>,
+    pub nics: BTreeMap<String, Map<String, Value>>,
+}
+
+// Point of the in-memory history kept by the monitor
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsSample {
+    pub timestamp: u64,
+    pub vcpu_seconds: f64,
+    pub host_rss_bytes: u64,
+    pub disk_read_bytes: u64,
+    pub disk_write_bytes: u64,
+    pub net_rx_bytes: u64,
+    pub net_tx_bytes: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StatsQuery {
+    #[serde(default)]
+    pub history: bool,
+}
+
+// mode is "rw" (default) for the interactive session or "ro" to observe
+#[derive(Debug, Default, Deserialize)]
+pub struct ConsoleQuery {
+    pub mode: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ConsoleLogQuery {
+    pub tail: Option<usize>,
+}
+
+// Last health check, vmm_state comes from the VMM and guest_reachable from the guest probes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VmHealth {
+    pub vmm_state: String,
+    pub guest_reachable: Option<bool>,
+    pub checked_at: u64,
+    pub probe_latency_ms: u64,
+}
+
+// Timings of the last monitor run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MonitorStats {
+    pub last_run_at: u64,
+    pub last_run_duration_ms: u64,
+    pub vms_checked: usize,
+    pub probes_timed_out: usize,
+}
+
+// Guest level check, the host defaults to the VM address
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum GuestProbe {
+    Tcp { port: u16, host: Option<String> },
+    Ping { host: Option<String> },
+}
+
+// VM definition persisted next to the VM config so it can be rebuilt later
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct VmSpec {
+    pub vm_id: i16,
+    pub uuid: String,
+    pub name: String,
+    pub fqdn: Option<String>,
+    pub metadata: Map<String, Value>,
+    pub seed_generation: u32,
+    pub pool: String,
+    pub project: Option<String>,
+    pub image: String,
+    pub cpu: i32,
+    pub ram: i32,
+    pub storage: String,
+    pub username: String,
+    pub password: String,
+    pub user_data: Option<String>,
+    pub vendor_data: Option<String>,
+    pub disks: Vec<DiskSpec>,
+    pub devices: Vec<DeviceSpec>,
+    pub mdevs: Vec<MdevSpec>,
+    pub probes: Vec<GuestProbe>,
+    pub host_drivers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DiskSpec {
+    pub id: String,
+    pub path: String,
+    pub size: u64,
+    #[serde(default)]
+    pub rate_limit: Option<DiskRateLimit>,
+}
+
+// Host PCI device attached when the VMM is launched
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DeviceSpec {
+    pub id: String,
+    pub address: String,
+}
+
+// Mediated device owned by the VM, recreated before launch when missing
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MdevSpec {
+    pub id: String,
+    pub uuid: String,
+    pub parent: String,
+    pub mdev_type: String,
+}
+
+// Bandwidth in bytes per second and operations per second
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DiskRateLimit {
+    pub bandwidth: Option<u64>,
+    pub iops: Option<u64>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct RequestVmData {
+    pub user_data: Option<String>,
+    pub vendor_data: Option<String>,
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+    pub rate_limit: Option<RequestRateLimitData>,
+    #[serde(default)]
+    pub devices: Vec<HostPci>,
+    #[serde(default)]
+    pub probes: Vec<GuestProbe>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestProbeData {
+    pub probes: Vec<GuestProbe>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestDiskSizeData {
+    pub size: String,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct RequestRateLimitData {
+    pub bandwidth: Option<String>,
+    pub iops: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestDiskData {
+    pub id: String,
+    pub size: String,
+    pub rate_limit: Option<RequestRateLimitData>,
+}
+
+// Storage pool and quota structure
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StoragePool {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub pools: Vec<StoragePool>,
+    pub vm_quota: Option<u64>,
+    pub project_quotas: BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestPoolData {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestQuotaData {
+    pub vm_quota: Option<String>,
+    #[serde(default)]
+    pub project_quotas: BTreeMap<String, String>,
 }
 
 // Host resource structure
-// pub struct HostResource {
-//     pub cpu_cores: usize,
-//     pub memory: f64,
-//     pub storage: f64,
-//     // devices avail
-// }
-
-// Device hardware and ticket structure
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostResource {
+    pub node: String,
+    pub cpu: HostCpu,
+    pub memory: HostMemory,
+    pub numa_nodes: Vec<NumaNode>,
+    pub pools: Vec<Value>,
+    pub kvm: bool,
+    pub iommu: bool,
+    pub hypervisor_version: Option<String>,
+    pub committed: CommittedResources,
+    pub policy: AdmissionPolicy,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostCpu {
+    pub model: Option<String>,
+    pub sockets: usize,
+    pub cores: usize,
+    pub threads: usize,
+    pub virtualization: Option<String>,
+}
+
+// Sizes in bytes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostMemory {
+    pub total: u64,
+    pub free: u64,
+    pub available: u64,
+    pub hugepages_total: u64,
+    pub hugepages_free: u64,
+    pub hugepage_size: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NumaNode {
+    pub id: u32,
+    pub cpus: String,
+    pub memory_total: u64,
+    pub memory_free: u64,
+}
+
+// Overcommit ratios and the share kept for the host, reserved_ram in bytes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AdmissionPolicy {
+    pub cpu_overcommit: f64,
+    pub ram_overcommit: f64,
+    pub reserved_cpus: u64,
+    pub reserved_ram: u64,
+}
+
+// Resources defined by the VM specs, ram and storage in bytes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommittedResources {
+    pub vms: usize,
+    pub cpus: u64,
+    pub ram: u64,
+    pub storage: u64,
+    pub pci_devices: usize,
+}
+
+// Device hardware structure
 #[derive(Deserialize, Serialize)]
 pub struct HostPci {
     pub address: String,
 }
 
+// Query filters of the PCI listing, ids and classes are hex
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PciFilter {
+    pub vendor: Option<String>,
+    pub device: Option<String>,
+    pub class: Option<String>,
+    pub subclass: Option<String>,
+    pub driver: Option<String>,
+    pub iommu_group: Option<String>,
+    pub assigned: Option<bool>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct HostGpu {
-    pub device_name: String,
-    pub amount: i32,
+    #[serde(alias = "device_name")]
+    pub model: String,
+    #[serde(alias = "amount")]
+    pub count: i32,
+    pub vendor: Option<String>,
+    #[serde(default)]
+    pub same_numa: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GpuInfo {
+    pub address: String,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub vendor_name: String,
+    pub model: String,
+    pub vram: Option<u64>,
+    pub numa_node: i32,
+    pub iommu_group: Option<String>,
+    pub companions: Vec<String>,
+    pub driver: Option<String>,
+    pub assigned_to: Option<PciAllocation>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct HostVf {
+    pub parent: String,
+    pub mac: Option<String>,
+    pub vlan: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VfInfo {
+    pub address: String,
+    pub parent: String,
+    pub index: u32,
+    pub driver: Option<String>,
+    pub mac: Option<String>,
+    pub iommu_group: Option<String>,
+    pub assigned_to: Option<PciAllocation>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SriovInfo {
+    pub address: String,
+    pub netdev: Option<String>,
+    pub driver: Option<String>,
+    pub total_vfs: u32,
+    pub num_vfs: u32,
+    pub vfs: Vec<VfInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MdevType {
+    pub id: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub device_api: Option<String>,
+    pub available_instances: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MdevParent {
+    pub address: String,
+    pub types: Vec<MdevType>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MdevInfo {
+    pub uuid: String,
+    pub parent: Option<String>,
+    pub mdev_type: Option<String>,
+    pub assigned_to: Option<i16>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct RequestPciData {
     pub hostpcis: Vec<HostPci>,
+    #[serde(default)]
+    pub include_group: bool,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -44,63 +383,127 @@ pub struct RequestGpuData {
     pub hostgpus: Vec<HostGpu>,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct RequestVfData {
+    pub hostvfs: Vec<HostVf>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestMdevData {
+    pub parent: Option<String>,
+    pub mdev_type: Option<String>,
+    pub uuid: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestSriovData {
+    pub num_vfs: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RequestVfConfigData {
+    pub mac: Option<String>,
+    pub vlan: Option<u16>,
+}
+
+// Host PCI device owned by a VM, keyed by its BDF in the ledger
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Ticket {
-    pub id: String,      
-    pub vm_id: i16,      
-    pub pcis_detail: Vec<Value>, 
-}
-
-// Ticket function
-pub fn generate_ticket(vm_id: i16, payload_str: String) -> String {
-    // Generate SHA-1 hash
-    let mut hasher = Sha1::new();
-    hasher.update(format!("{}{}", vm_id, payload_str));
-    let ticket_id = format!("{:x}", hasher.finalize());
-
-    // Log the added ticket
-    ticket_id
-}
-
-pub fn store_ticket(vm_id: i16, ticket_id: &str, pcis_detail: Vec<Value>,
-                    ticket_list: &Arc<Mutex<LinkedList<Ticket>>>) {
-    // Create a new ticket
-    let ticket = Ticket {
-        id: ticket_id.to_string(),
-        vm_id,
-        pcis_detail: pcis_detail,
-    };
-
-    {
-        let mut list = ticket_list.lock().unwrap();
-        list.push_back(ticket.clone());
+pub struct PciAllocation {
+    pub vm_id: i16,
+    pub device_id: String,
+}
+
+pub type PciLedger = BTreeMap<String, PciAllocation>;
+
+const PCI_LEDGER_PATH: &str = "../pci-ledger.json";
+
+// PCI ledger function
+pub fn load_pci_ledger() -> PciLedger {
+    fs::read_to_string(PCI_LEDGER_PATH).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_pci_ledger(ledger: &PciLedger) {
+    match serde_json::to_string_pretty(ledger) {
+        Ok(content) => {
+            if let Err(e) = fs::write(PCI_LEDGER_PATH, content) {
+                error!("Cannot write {}: {}", PCI_LEDGER_PATH, e);
+            }
+        }
+        Err(e) => error!("Cannot serialize the pci ledger: {}", e),
     }
 }
 
-pub fn find_ticket(ticket_id: &str, ticket_list: &Arc<Mutex<LinkedList<Ticket>>>) -> Option<Ticket> {
-    let list = ticket_list.lock().unwrap();
+// Reserve a device for a VM, the device id is filled in once it is attached
+// Returns true when this call created the entry, false when the VM already owned the device
+pub fn allocate_pci(bdf: &str, vm_id: i16, pci_ledger: &Arc<Mutex<PciLedger>>) -> Result<bool, String> {
+    let mut ledger = pci_ledger.lock().unwrap();
+    if let Some(allocation) = ledger.get(bdf) {
+        if allocation.vm_id != vm_id {
+            return Err(format!("The device {} is already assigned to vm_id: {}", bdf, allocation.vm_id));
+        }
+        return Ok(false);
+    }
 
-    for ticket in list.iter() {
-        if ticket.id == ticket_id {
-            return Some(ticket.clone());
+    ledger.insert(bdf.to_string(), PciAllocation { vm_id, device_id: String::new() });
+    save_pci_ledger(&ledger);
+    Ok(true)
+}
+
+// Reserve devices with their device ids under a single lock, all of them or none
+pub fn allocate_pcis(devices: &[(&str, &str)], vm_id: i16, pci_ledger: &Arc<Mutex<PciLedger>>) 
+                    -> Result<(), String> {
+    let mut ledger = pci_ledger.lock().unwrap();
+    for (bdf, _) in devices {
+        if let Some(allocation) = ledger.get(*bdf) {
+            if allocation.vm_id != vm_id {
+                return Err(format!("The device {} is already assigned to vm_id: {}", bdf, allocation.vm_id));
+            }
         }
     }
 
-    None
+    for (bdf, device_id) in devices {
+        ledger.insert(bdf.to_string(), PciAllocation { vm_id, device_id: device_id.to_string() });
+    }
+    save_pci_ledger(&ledger);
+    Ok(())
+}
+
+pub fn set_pci_device_id(bdf: &str, device_id: &str, pci_ledger: &Arc<Mutex<PciLedger>>) {
+    let mut ledger = pci_ledger.lock().unwrap();
+    if let Some(allocation) = ledger.get_mut(bdf) {
+        allocation.device_id = device_id.to_string();
+        save_pci_ledger(&ledger);
+    }
 }
 
-pub fn remove_ticket(ticket_id: &str, ticket_list: &Arc<Mutex<LinkedList<Ticket>>>) -> bool {
-    let mut list = ticket_list.lock().unwrap(); // Lock the list for thread safety
-    let original_len = list.len(); // Store original length before modification
+pub fn release_pci(bdf: &str, vm_id: i16, pci_ledger: &Arc<Mutex<PciLedger>>) -> bool {
+    let mut ledger = pci_ledger.lock().unwrap();
+    match ledger.get(bdf) {
+        Some(allocation) if allocation.vm_id == vm_id => {
+            ledger.remove(bdf);
+            save_pci_ledger(&ledger);
+            true
+        }
+        _ => false,
+    }
+}
 
-    // Rebuild the list, skipping the ticket with matching ID
-    *list = list.iter()
-        .filter(|ticket| ticket.id != ticket_id) // Keep only tickets that don’t match
-        .cloned()
-        .collect();
+pub fn find_pci_by_device_id(vm_id: i16, device_id: &str, pci_ledger: &Arc<Mutex<PciLedger>>) 
+                            -> Option<String> {
+    let ledger = pci_ledger.lock().unwrap();
+    ledger.iter()
+        .find(|(_, allocation)| allocation.vm_id == vm_id && allocation.device_id == device_id)
+        .map(|(bdf, _)| bdf.clone())
+}
 
-    // Return true if a ticket was removed, false if not found
-    original_len != list.len()
+pub fn find_vm_pcis(vm_id: i16, pci_ledger: &Arc<Mutex<PciLedger>>) -> Vec<String> {
+    let ledger = pci_ledger.lock().unwrap();
+    ledger.iter()
+        .filter(|(_, allocation)| allocation.vm_id == vm_id)
+        .map(|(bdf, _)| bdf.clone())
+        .collect()
 }
 
 pub fn init_vm_vec(vm_vec: &Arc<Mutex<Vec<VmStatus>>>) {
@@ -111,6 +514,10 @@ pub fn init_vm_vec(vm_vec: &Arc<Mutex<Vec<VmStatus>>>) {
             process_id: String::from("").into_boxed_str(),
             status: -1,
             lost_signal_count: 2,
+            health: VmHealth::default(),
+            history: VecDeque::new(),
+            ready_at: None,
+            launch: 0,
         });
     }
 }
@@ -124,12 +531,15 @@ pub fn find_free_slot(vm_vec: &Arc<Mutex<Vec<VmStatus>>>) -> i16 {
             return i.try_into().unwrap();
         }
     }
-    return -1;
+    -1
 }   
 
 pub fn mark_vm_stop(mut vm_vec: MutexGuard<'_, Vec<VmStatus>>, vm_id: usize) {
     vm_vec[vm_id].status = 0;
     vm_vec[vm_id].process_id = "".into();
     vm_vec[vm_id].lost_signal_count = 3;
-    println!("vm_id: {} has no signal", vm_id);  
+    vm_vec[vm_id].health = VmHealth::default();
+    vm_vec[vm_id].history.clear();
+    vm_vec[vm_id].ready_at = None;
+    warn!("vm_id: {} has no signal", vm_id);  
 }
\ No newline at end of file
@@ -0,0 +1,330 @@
+use std::{
+    env,
+    fs,
+    io::ErrorKind,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+use tracing::{error, info};
+
+const VFIO_DRIVER: &str = "vfio-pci";
+
+// The sysfs root can be pointed at a fake tree through CHV_SYSFS_ROOT
+pub fn sysfs_root() -> String {
+    env::var("CHV_SYSFS_ROOT").unwrap_or("/sys".to_string())
+}
+
+pub fn pci_device_path(bdf: &str) -> String {
+    format!("{}/bus/pci/devices/{}", sysfs_root(), bdf)
+}
+
+// Accept both bus:dev.fn and domain:bus:dev.fn addresses, the result is safe to put in a sysfs path
+pub fn normalize_bdf(address: &str) -> Result<String, String> {
+    let lower = address.trim().to_ascii_lowercase();
+    let bdf = if lower.matches(':').count() == 1 {
+        format!("0000:{}", lower)
+    } else {
+        lower
+    };
+    if !is_valid_bdf(&bdf) {
+        return Err(format!("'{}' is not a valid PCI address", address));
+    }
+    Ok(bdf)
+}
+
+// Exactly dddd:bb:dd.f in lowercase hex, such as 0000:3b:00.1
+pub fn is_valid_bdf(bdf: &str) -> bool {
+    let bytes = bdf.as_bytes();
+    bytes.len() == 12 && bytes.iter().enumerate().all(|(index, c)| match index {
+        4 | 7 => *c == b':',
+        10 => *c == b'.',
+        11 => (b'0'..=b'7').contains(c),
+        _ => c.is_ascii_digit() || (b'a'..=b'f').contains(c),
+    })
+}
+
+// sysfs attributes are root only, fall back to sudo when the controller is not root
+pub fn write_sysfs(path: &str, value: &str) -> Result<(), String> {
+    match fs::write(path, value) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let mut child = Command::new("sudo")
+                .arg("tee")
+                .arg(path)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()
+                .map_err(|e| format!("Failed to execute command: {}", e))?;
+            if let Some(stdin) = child.stdin.as_mut() {
+                let _ = stdin.write_all(value.as_bytes());
+            }
+            match child.wait() {
+                Ok(status) if status.success() => Ok(()),
+                _ => Err(format!("Cannot write '{}' to {}", value.trim(), path)),
+            }
+        }
+        Err(e) => Err(format!("Cannot write '{}' to {}: {}", value.trim(), path, e)),
+    }
+}
+
+pub fn get_pci_driver(bdf: &str) -> Option<String> {
+    fs::read_link(format!("{}/driver", pci_device_path(bdf))).ok()
+        .and_then(|link| link.file_name().map(|name| name.to_string_lossy().to_string()))
+}
+
+fn unbind_driver(bdf: &str) -> Result<(), String> {
+    if get_pci_driver(bdf).is_none() {
+        return Ok(());
+    }
+    write_sysfs(&format!("{}/driver/unbind", pci_device_path(bdf)), bdf)
+}
+
+fn set_driver_override(bdf: &str, driver: &str) -> Result<(), String> {
+    // A single newline clears the override
+    let value = if driver.is_empty() { "\n" } else { driver };
+    write_sysfs(&format!("{}/driver_override", pci_device_path(bdf)), value)
+}
+
+// Bind the device to vfio-pci and return the host driver to restore later,
+// an empty driver means the device was unbound and the kernel should probe it
+pub fn bind_vfio(bdf: &str) -> Result<Option<String>, String> {
+    if !is_valid_bdf(bdf) {
+        return Err(format!("'{}' is not a valid PCI address", bdf));
+    }
+    if !Path::new(&pci_device_path(bdf)).exists() {
+        return Err(format!("PCI device {} not found", bdf));
+    }
+
+    let original = get_pci_driver(bdf);
+    if original.as_deref() == Some(VFIO_DRIVER) {
+        info!("The device {} is already bound to {}", bdf, VFIO_DRIVER);
+        return Ok(None);
+    }
+
+    let vfio_path = format!("{}/bus/pci/drivers/{}", sysfs_root(), VFIO_DRIVER);
+    if !Path::new(&vfio_path).exists() {
+        return Err(format!("The {} driver is not loaded", VFIO_DRIVER));
+    }
+
+    info!("Binding the device {} from {:?} to {}", bdf, original, VFIO_DRIVER);
+    let bound = set_driver_override(bdf, VFIO_DRIVER)
+        .and_then(|_| unbind_driver(bdf))
+        .and_then(|_| write_sysfs(&format!("{}/bind", vfio_path), bdf))
+        .and_then(|_| match get_pci_driver(bdf).as_deref() {
+            Some(VFIO_DRIVER) => Ok(()),
+            _ => Err(format!("The device {} did not bind to {}", bdf, VFIO_DRIVER)),
+        });
+
+    if let Err(e) = bound {
+        // Clear the override and hand the device back so it is not left without a driver
+        if let Err(restore) = restore_driver(bdf, original.as_deref()) {
+            error!("Cannot restore the device {} to {:?}: {}", bdf, original, restore);
+        }
+        return Err(e);
+    }
+    Ok(Some(original.unwrap_or_default()))
+}
+
+// Give the device back to its original host driver, or let the kernel probe one
+pub fn restore_driver(bdf: &str, original: Option<&str>) -> Result<(), String> {
+    if !is_valid_bdf(bdf) {
+        return Err(format!("'{}' is not a valid PCI address", bdf));
+    }
+    info!("Restoring the device {} to {:?}", bdf, original);
+    unbind_driver(bdf)?;
+    set_driver_override(bdf, "")?;
+
+    match original {
+        Some(driver) if !driver.is_empty() => {
+            write_sysfs(&format!("{}/bus/pci/drivers/{}/bind", sysfs_root(), driver), bdf)
+        }
+        _ => write_sysfs(&format!("{}/bus/pci/drivers_probe", sysfs_root()), bdf),
+    }
+}
+
+// Read a single value attribute of the device such as numa_node or current_link_speed
+pub fn read_pci_attr(bdf: &str, attr: &str) -> Option<String> {
+    fs::read_to_string(format!("{}/{}", pci_device_path(bdf), attr)).ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+pub fn get_numa_node(bdf: &str) -> i32 {
+    read_pci_attr(bdf, "numa_node")
+        .and_then(|node| node.parse().ok())
+        .unwrap_or(-1)
+}
+
+pub fn get_iommu_group(bdf: &str) -> Option<String> {
+    fs::read_link(format!("{}/iommu_group", pci_device_path(bdf))).ok()
+        .and_then(|link| link.file_name().map(|name| name.to_string_lossy().to_string()))
+}
+
+pub fn get_iommu_group_members(group: &str) -> Vec<String> {
+    let mut members: Vec<String> = fs::read_dir(format!("{}/kernel/iommu_groups/{}/devices", 
+                                                        sysfs_root(), group))
+        .map(|entries| entries.flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect())
+        .unwrap_or_default();
+    members.sort();
+    members
+}
+
+// PCI-to-PCI bridges (normal 0x0604 and semi-transparent 0x0609) stay with the host,
+// vfio does not require them to be bound. Other bridge subclasses are regular endpoints
+pub fn is_pci_bridge(bdf: &str) -> bool {
+    fs::read_to_string(format!("{}/class", pci_device_path(bdf)))
+        .map(|class| {
+            let class = class.trim().trim_start_matches("0x");
+            class.starts_with("0604") || class.starts_with("0609")
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{
+        os::unix::fs::symlink,
+        sync::{Mutex, MutexGuard},
+        thread,
+    };
+    use tempfile::TempDir;
+
+    static SYSFS_LOCK: Mutex<()> = Mutex::new(());
+
+    // Point CHV_SYSFS_ROOT at an empty tree, the guard keeps other tests off the variable
+    pub fn fake_sysfs() -> (MutexGuard<'static, ()>, TempDir) {
+        let guard = SYSFS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let root = tempfile::tempdir().unwrap();
+        env::set_var("CHV_SYSFS_ROOT", root.path());
+        (guard, root)
+    }
+
+    pub fn add_driver(root: &Path, driver: &str) {
+        fs::create_dir_all(root.join("bus/pci/drivers").join(driver)).unwrap();
+    }
+
+    pub fn add_device(root: &Path, bdf: &str, driver: Option<&str>) {
+        let device = root.join("bus/pci/devices").join(bdf);
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("driver_override"), "(null)\n").unwrap();
+        if let Some(driver) = driver {
+            add_driver(root, driver);
+            symlink(root.join("bus/pci/drivers").join(driver), device.join("driver")).unwrap();
+        }
+    }
+
+    #[test]
+    fn normalize_bdf_adds_the_domain() {
+        assert_eq!(normalize_bdf("01:00.0"), Ok("0000:01:00.0".to_string()));
+        assert_eq!(normalize_bdf(" 0000:3B:00.1 "), Ok("0000:3b:00.1".to_string()));
+        assert_eq!(normalize_bdf("0001:02:00.7"), Ok("0001:02:00.7".to_string()));
+    }
+
+    #[test]
+    fn normalize_bdf_rejects_paths() {
+        for address in ["0000:00:02.0/../../../../../etc", "../0000:00:02.0", "0000:00:02.8", 
+                        "0000:0g:02.0", "00:02.0:1", "0000:00:02", ""] {
+            assert!(normalize_bdf(address).is_err(), "{} was accepted", address);
+        }
+    }
+
+    #[test]
+    fn bind_vfio_rejects_invalid_addresses() {
+        let (_guard, root) = fake_sysfs();
+        add_driver(root.path(), VFIO_DRIVER);
+
+        assert!(bind_vfio("0000:00:02.0/../../../../../etc").unwrap_err().contains("not a valid PCI address"));
+        assert!(restore_driver("../../etc", None).is_err());
+        assert!(!root.path().join("etc").exists());
+    }
+
+    #[test]
+    fn iommu_group_members_are_sorted() {
+        let (_guard, root) = fake_sysfs();
+        let devices = root.path().join("kernel/iommu_groups/7/devices");
+        fs::create_dir_all(&devices).unwrap();
+        for bdf in ["0000:01:00.1", "0000:01:00.0"] {
+            fs::write(devices.join(bdf), "").unwrap();
+        }
+
+        assert_eq!(get_iommu_group_members("7"), vec!["0000:01:00.0", "0000:01:00.1"]);
+        assert!(get_iommu_group_members("8").is_empty());
+    }
+
+    #[test]
+    fn only_pci_to_pci_bridges_are_exempt() {
+        let (_guard, root) = fake_sysfs();
+        for (bdf, class) in [("0000:00:01.0", "0x060400"), ("0000:00:02.0", "0x060901"),
+                             ("0000:00:03.0", "0x060000"), ("0000:00:04.0", "0x068000"),
+                             ("0000:00:05.0", "0x010802")] {
+            add_device(root.path(), bdf, None);
+            fs::write(root.path().join("bus/pci/devices").join(bdf).join("class"), class).unwrap();
+        }
+
+        assert!(is_pci_bridge("0000:00:01.0"));
+        assert!(is_pci_bridge("0000:00:02.0"));
+        assert!(!is_pci_bridge("0000:00:03.0"));
+        assert!(!is_pci_bridge("0000:00:04.0"));
+        assert!(!is_pci_bridge("0000:00:05.0"));
+        assert!(!is_pci_bridge("0000:00:06.0"));
+    }
+
+    #[test]
+    fn bind_vfio_moves_the_device() {
+        let (_guard, root) = fake_sysfs();
+        let bdf = "0000:01:00.0";
+        add_device(root.path(), bdf, Some("nvme"));
+        add_driver(root.path(), VFIO_DRIVER);
+
+        // The kernel switches the driver link on bind, the fifos let the test do it in order:
+        // the write to vfio-pci/bind cannot open until the link points at vfio-pci
+        let drivers = root.path().join("bus/pci/drivers");
+        let unbind = drivers.join("nvme/unbind");
+        let bind = drivers.join(VFIO_DRIVER).join("bind");
+        for fifo in [&unbind, &bind] {
+            nix::unistd::mkfifo(fifo, nix::sys::stat::Mode::S_IRWXU).unwrap();
+        }
+        let link = root.path().join("bus/pci/devices").join(bdf).join("driver");
+        let kernel = thread::spawn(move || {
+            let unbound = fs::read_to_string(&unbind).unwrap();
+            fs::remove_file(&link).unwrap();
+            symlink(bind.parent().unwrap(), &link).unwrap();
+            (unbound, fs::read_to_string(&bind).unwrap())
+        });
+
+        assert_eq!(bind_vfio(bdf), Ok(Some("nvme".to_string())));
+        assert_eq!(kernel.join().unwrap(), (bdf.to_string(), bdf.to_string()));
+        assert_eq!(get_pci_driver(bdf).as_deref(), Some(VFIO_DRIVER));
+        let device = root.path().join("bus/pci/devices").join(bdf);
+        assert_eq!(fs::read_to_string(device.join("driver_override")).unwrap(), VFIO_DRIVER);
+    }
+
+    #[test]
+    fn bind_vfio_restores_the_driver_on_failure() {
+        let (_guard, root) = fake_sysfs();
+        let bdf = "0000:02:00.0";
+        add_device(root.path(), bdf, Some("nvme"));
+        add_driver(root.path(), VFIO_DRIVER);
+
+        // Nothing moves the driver link, so the device never shows up under vfio-pci
+        assert!(bind_vfio(bdf).is_err());
+
+        let device = root.path().join("bus/pci/devices").join(bdf);
+        let drivers = root.path().join("bus/pci/drivers");
+        assert_eq!(fs::read_to_string(device.join("driver_override")).unwrap(), "\n");
+        assert_eq!(fs::read_to_string(drivers.join("nvme/bind")).unwrap(), bdf);
+    }
+
+    #[test]
+    fn bind_vfio_requires_the_driver() {
+        let (_guard, root) = fake_sysfs();
+        add_device(root.path(), "0000:03:00.0", Some("nvme"));
+
+        assert!(bind_vfio("0000:03:00.0").unwrap_err().contains("not loaded"));
+        assert!(bind_vfio("0000:04:00.0").unwrap_err().contains("not found"));
+    }
+}
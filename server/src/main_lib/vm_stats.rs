@@ -0,0 +1,139 @@
+use std::{
+    sync::{Arc, Mutex},
+    collections::BTreeMap,
+    time::Duration,
+    fs,
+};
+use serde_json::{Map, Value};
+
+use crate::main_lib::structure::{VmStatus, VmStats, StatsSample};
+use crate::main_lib::manage_vm::call_vmm_api_with_timeout;
+use crate::main_lib::health::{is_vmm_process_alive, unix_now};
+
+// /proc reports times in USER_HZ ticks, fixed at 100 by the Linux ABI
+const USER_HZ: f64 = 100.0;
+const STATS_API_TIMEOUT: Duration = Duration::from_secs(2);
+const HISTORY_LEN: usize = 60;
+
+// Fields of /proc/[pid]/stat after the command name, utime is the 12th
+fn read_stat_fields(path: &str) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(path).ok()?;
+    let (_, rest) = stat.rsplit_once(')')?;
+    Some(rest.split_whitespace().map(|field| field.to_string()).collect())
+}
+
+fn cpu_seconds(fields: &[String]) -> f64 {
+    let utime: f64 = fields.get(11).and_then(|field| field.parse().ok()).unwrap_or(0.0);
+    let stime: f64 = fields.get(12).and_then(|field| field.parse().ok()).unwrap_or(0.0);
+    (utime + stime) / USER_HZ
+}
+
+// cloud-hypervisor names its vCPU threads vcpu0, vcpu1..
+fn get_vcpu_times(pid: &str) -> BTreeMap<String, f64> {
+    let mut vcpus = BTreeMap::new();
+    let tasks = match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks,
+        Err(_) => return vcpus,
+    };
+
+    for task in tasks.flatten() {
+        let path = task.path().to_string_lossy().to_string();
+        let comm = fs::read_to_string(format!("{}/comm", path)).unwrap_or_default();
+        let comm = comm.trim();
+        if !comm.starts_with("vcpu") {
+            continue;
+        }
+        if let Some(fields) = read_stat_fields(&format!("{}/stat", path)) {
+            vcpus.insert(comm.to_string(), cpu_seconds(&fields));
+        }
+    }
+    vcpus
+}
+
+fn get_rss_bytes(pid: &str) -> u64 {
+    fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default()
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .unwrap_or(0)
+}
+
+// starttime is the 22nd field, in ticks since the host booted
+fn get_uptime_seconds(pid: &str) -> f64 {
+    let host_uptime: f64 = fs::read_to_string("/proc/uptime").ok()
+        .and_then(|uptime| uptime.split_whitespace().next().and_then(|value| value.parse().ok()))
+        .unwrap_or(0.0);
+    let start = read_stat_fields(&format!("/proc/{}/stat", pid))
+        .and_then(|fields| fields.get(19).and_then(|field| field.parse::<f64>().ok()))
+        .unwrap_or(0.0);
+    (host_uptime - start / USER_HZ).max(0.0)
+}
+
+pub fn collect_vm_stats(vm_id: i16, pid: &str) -> Result<VmStats, String> {
+    if !is_vmm_process_alive(vm_id, pid) {
+        return Err(format!("vm_id: {} is not running", vm_id));
+    }
+
+    let vcpus = get_vcpu_times(pid);
+    let vcpu_seconds = vcpus.values().sum();
+    let mut stats = VmStats {
+        vm_id,
+        pid: pid.to_string(),
+        uptime_seconds: get_uptime_seconds(pid),
+        vcpu_seconds,
+        vcpus,
+        host_rss_bytes: get_rss_bytes(pid),
+        ..Default::default()
+    };
+
+    let info: Value = call_vmm_api_with_timeout(vm_id, "GET", "vm.info", None, STATS_API_TIMEOUT).ok()
+        .and_then(|info| serde_json::from_str(&info).ok())
+        .unwrap_or_default();
+    stats.memory_size = info["config"]["memory"]["size"].as_u64();
+    stats.memory_actual_size = info["memory_actual_size"].as_u64();
+    stats.balloon_size = info["config"]["balloon"]["size"].as_u64();
+
+    // Disks report read_bytes and write_bytes, NICs rx_bytes and tx_bytes
+    let counters: Value = call_vmm_api_with_timeout(vm_id, "GET", "vm.counters", None, STATS_API_TIMEOUT)
+        .ok()
+        .and_then(|counters| serde_json::from_str(&counters).ok())
+        .unwrap_or_default();
+    for (device, values) in counters.as_object().into_iter().flatten() {
+        let values = match values.as_object() {
+            Some(values) => values.clone(),
+            None => continue,
+        };
+        if values.contains_key("read_bytes") {
+            stats.disks.insert(device.clone(), values);
+        } else if values.contains_key("rx_bytes") {
+            stats.nics.insert(device.clone(), values);
+        }
+    }
+    Ok(stats)
+}
+
+fn sum_counter(devices: &BTreeMap<String, Map<String, Value>>, counter: &str) -> u64 {
+    devices.values().filter_map(|values| values.get(counter).and_then(|value| value.as_u64())).sum()
+}
+
+// Keep the last HISTORY_LEN samples of the VM
+pub fn record_stats_sample(vm_vec: &Arc<Mutex<Vec<VmStatus>>>, vm_id: usize, stats: &VmStats) {
+    let sample = StatsSample {
+        timestamp: unix_now(),
+        vcpu_seconds: stats.vcpu_seconds,
+        host_rss_bytes: stats.host_rss_bytes,
+        disk_read_bytes: sum_counter(&stats.disks, "read_bytes"),
+        disk_write_bytes: sum_counter(&stats.disks, "write_bytes"),
+        net_rx_bytes: sum_counter(&stats.nics, "rx_bytes"),
+        net_tx_bytes: sum_counter(&stats.nics, "tx_bytes"),
+    };
+
+    let mut vm_vec = vm_vec.lock().unwrap();
+    let history = &mut vm_vec[vm_id].history;
+    if history.len() >= HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(sample);
+}